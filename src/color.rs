@@ -0,0 +1,125 @@
+use math::{Vec3f, Vec4f};
+use ppm;
+
+// sRGB transfer functions, see IEC 61966-2-1.
+// Shading happens in linear space, textures and outputs are stored in sRGB.
+
+pub fn srgb_to_linear(c: f32) -> f32 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+pub fn linear_to_srgb(c: f32) -> f32 {
+    if c <= 0.003_130_8 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+pub fn decode_srgb8(r: u8, g: u8, b: u8) -> Vec3f {
+    let decode = |c: u8| srgb_to_linear(f32::from(c) / 255.0);
+    Vec3f::new(decode(r), decode(g), decode(b))
+}
+
+pub fn encode_srgb8(c: Vec3f) -> ppm::RGB {
+    let encode = |c: f32| -> u8 {
+        let c = linear_to_srgb(c.clamp(0.0, 1.0));
+        (c * 255.0 + 0.5) as u8
+    };
+    ppm::RGB::new(encode(c.x), encode(c.y), encode(c.z))
+}
+
+// Straight alpha 8-bit color to linear color with premultiplied alpha
+pub fn decode_srgba8(c: ppm::RGB) -> Vec4f {
+    let a = f32::from(c.a) / 255.0;
+    Vec4f::from_vec3f(decode_srgb8(c.r, c.g, c.b) * a, a)
+}
+
+// Linear color with premultiplied alpha to straight alpha 8-bit color
+pub fn encode_srgba8(c: Vec4f) -> ppm::RGB {
+    let a = c.w.clamp(0.0, 1.0);
+    let rgb = if a > 0.0 { c.xyz() * (1.0 / a) } else { c.xyz() };
+    let res = encode_srgb8(rgb);
+    ppm::RGB::new_rgba(res.r, res.g, res.b, (a * 255.0 + 0.5) as u8)
+}
+
+// How a fragment is combined with the color already in the target.
+// Targets store linear colors with premultiplied alpha.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BlendMode {
+    // Overwrites the destination
+    Replace,
+    // Source has straight alpha, composited over the destination
+    Alpha,
+    // Source has premultiplied alpha, composited over the destination
+    Premultiplied,
+    // Source weighted by its alpha is added to the destination
+    Additive,
+    // Destination is modulated by the source, weighted by its alpha
+    Multiply,
+}
+
+impl BlendMode {
+    pub fn blend(self, src: Vec4f, dst: Vec4f) -> Vec4f {
+        let over_alpha = src.w + dst.w * (1.0 - src.w);
+        match self {
+            BlendMode::Replace => Vec4f::from_vec3f(src.xyz() * src.w, src.w),
+            BlendMode::Alpha => {
+                Vec4f::from_vec3f(src.xyz() * src.w + dst.xyz() * (1.0 - src.w), over_alpha)
+            }
+            BlendMode::Premultiplied => {
+                Vec4f::from_vec3f(src.xyz() + dst.xyz() * (1.0 - src.w), over_alpha)
+            }
+            BlendMode::Additive => Vec4f::from_vec3f(src.xyz() * src.w + dst.xyz(), over_alpha),
+            BlendMode::Multiply => {
+                let factor = src.xyz() * src.w + Vec3f::new(1.0, 1.0, 1.0) * (1.0 - src.w);
+                Vec4f::from_vec3f(dst.xyz() * factor, dst.w)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn srgb_roundtrip_test() {
+        for i in 0..=255u8 {
+            let c = decode_srgb8(i, i, i);
+            let e = encode_srgb8(c);
+            assert_eq!(e.r, i);
+        }
+    }
+
+    #[test]
+    fn srgb_encode_clamps_test() {
+        let e = encode_srgb8(Vec3f::new(2.0, -1.0, 1.0));
+        assert_eq!((e.r, e.g, e.b), (255, 0, 255));
+    }
+
+    #[test]
+    fn blend_test() {
+        let dst = Vec4f::new(0.0, 0.0, 1.0, 1.0);
+        let src = Vec4f::new(1.0, 0.0, 0.0, 0.25);
+
+        let res = BlendMode::Alpha.blend(src, dst);
+        assert_eq!((res.x, res.y, res.z, res.w), (0.25, 0.0, 0.75, 1.0));
+
+        let res = BlendMode::Premultiplied.blend(src, dst);
+        assert_eq!((res.x, res.y, res.z, res.w), (1.0, 0.0, 0.75, 1.0));
+
+        let res = BlendMode::Additive.blend(src, dst);
+        assert_eq!((res.x, res.y, res.z, res.w), (0.25, 0.0, 1.0, 1.0));
+
+        let res = BlendMode::Multiply.blend(src, dst);
+        assert_eq!((res.x, res.y, res.z, res.w), (0.0, 0.0, 0.75, 1.0));
+
+        let res = BlendMode::Replace.blend(src, dst);
+        assert_eq!((res.x, res.y, res.z, res.w), (0.25, 0.0, 0.0, 0.25));
+    }
+}
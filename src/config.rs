@@ -0,0 +1,754 @@
+use color::BlendMode;
+use light::{DirectionalLight, Light, PointLight, SpotLight};
+use math::{Vec3f, Vec4f};
+use pbr::MaterialSettings;
+use raster::{CullMode, FrontFace, RasterizerState};
+use resample::Filter;
+use shadow::{ShadowFilter, ShadowFilterParameters, ShadowSettings};
+use sky::SkySettings;
+use ssao::SsaoSettings;
+use tonemap::{ToneMapOperator, ToneMapping};
+
+// Render configuration, filled from the command line arguments
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RenderMode {
+    Shaded,
+    // Only the triangle edges, depth tested against each other
+    Wireframe,
+    // Triangle edges drawn over the shaded mesh
+    Overlay,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ShadingModel {
+    // Phong with the specular exponent from the specular map
+    Phong,
+    // Metallic-roughness, see `pbr`
+    Pbr,
+}
+
+#[derive(Debug, Clone)]
+pub struct RenderConfig {
+    pub tone_mapping: ToneMapping,
+    // Clear color, linear with straight alpha
+    pub background: Vec4f,
+    // Blend mode used when drawing the mesh
+    pub blend_mode: BlendMode,
+    // Turns the mesh material into a cutout, discarding the fragments
+    // whose diffuse alpha is below the cutoff
+    pub alpha_cutoff: Option<f32>,
+    // Draws the mesh with order independent transparency
+    pub order_independent: bool,
+    pub rasterizer: RasterizerState,
+    // Disables culling for the mesh material
+    pub two_sided: bool,
+    // Number of samples per pixel, 1 disables MSAA
+    pub msaa_samples: usize,
+    // Scale of the internal framebuffer compared to the output image
+    pub supersampling: usize,
+    // Filter used to downsample the supersampled image
+    pub resample_filter: Filter,
+    pub render_mode: RenderMode,
+    // Wireframe lines color, linear with straight alpha
+    pub line_color: Vec4f,
+    // Wireframe lines width, in pixels of the internal framebuffer
+    pub line_width: f32,
+    // Text stamped in the top left corner of the output image
+    pub label: Option<String>,
+    // Also stamps the model name, triangle count and render time
+    pub stats: bool,
+    pub label_scale: usize,
+    pub shadow: ShadowSettings,
+    // Renders the scene with several shadow filters, side by side
+    pub compare_shadows: bool,
+    // Lights of the scene, the direct lights cast shadows
+    pub lights: Vec<Light>,
+    // Equirectangular HDR map in the resource directory, lighting the scene
+    // on top of the lights
+    pub environment: Option<String>,
+    pub environment_intensity: f32,
+    // Drawn behind the mesh instead of the background color
+    pub sky: Option<SkySettings>,
+    pub shading: ShadingModel,
+    // Used by the PBR shading
+    pub material: MaterialSettings,
+    // Screen space ambient occlusion, darkening the ambient light
+    pub ssao: SsaoSettings,
+    // Lights the mesh in a pass over a G-buffer instead of in its fragments
+    pub deferred: bool,
+}
+
+impl Default for RenderConfig {
+    fn default() -> RenderConfig {
+        RenderConfig {
+            tone_mapping: ToneMapping::default(),
+            background: Vec4f::new(0.0, 0.0, 0.0, 1.0),
+            blend_mode: BlendMode::Replace,
+            alpha_cutoff: None,
+            order_independent: false,
+            rasterizer: RasterizerState::default(),
+            two_sided: false,
+            msaa_samples: 1,
+            supersampling: 1,
+            resample_filter: Filter::Mitchell,
+            render_mode: RenderMode::Shaded,
+            line_color: Vec4f::new(1.0, 1.0, 1.0, 1.0),
+            line_width: 1.0,
+            label: None,
+            stats: false,
+            label_scale: 1,
+            shadow: ShadowSettings::default(),
+            compare_shadows: false,
+            lights: vec![
+                Light::Directional(DirectionalLight::new(Vec3f::new(1.0, 1.0, 0.0))),
+                Light::Ambient { color: Vec3f::new(1.0, 1.0, 1.0), intensity: 0.15 },
+            ],
+            environment: None,
+            environment_intensity: 1.0,
+            sky: None,
+            shading: ShadingModel::Phong,
+            material: MaterialSettings::default(),
+            ssao: SsaoSettings::default(),
+            deferred: false,
+        }
+    }
+}
+
+fn parse_value<T: std::str::FromStr>(name: &str, value: Option<String>) -> Result<T, String> {
+    let value = value.ok_or_else(|| format!("Missing value for {}", name))?;
+    value
+        .parse::<T>()
+        .map_err(|_| format!("Invalid value '{}' for {}", value, name))
+}
+
+// Comma separated list of floats, eg. "0.2,0.2,0.2,0.0"
+fn parse_floats(name: &str, value: Option<String>, count: usize) -> Result<Vec<f32>, String> {
+    let value: String = parse_value(name, value)?;
+    let comp = value
+        .split(',')
+        .map(|c| c.trim().parse::<f32>())
+        .collect::<Result<Vec<f32>, _>>()
+        .map_err(|_| format!("Invalid value '{}' for {}", value, name))?;
+    if comp.len() != count {
+        return Err(format!("Expected {} components for {}, got '{}'", count, name, value));
+    }
+    Ok(comp)
+}
+
+fn parse_vec3f(name: &str, value: Option<String>) -> Result<Vec3f, String> {
+    let comp = parse_floats(name, value, 3)?;
+    Ok(Vec3f::new(comp[0], comp[1], comp[2]))
+}
+
+fn parse_vec4f(name: &str, value: Option<String>) -> Result<Vec4f, String> {
+    let comp = parse_floats(name, value, 4)?;
+    Ok(Vec4f::new(comp[0], comp[1], comp[2], comp[3]))
+}
+
+// The light options apply to the last light added
+fn last_light<'a>(config: &'a mut RenderConfig, name: &str) -> Result<&'a mut Light, String> {
+    config
+        .lights
+        .last_mut()
+        .ok_or_else(|| format!("{} must follow a light", name))
+}
+
+fn last_spot_light<'a>(config: &'a mut RenderConfig, name: &str) -> Result<&'a mut SpotLight, String> {
+    match config.lights.last_mut() {
+        Some(Light::Spot(light)) => Ok(light),
+        _ => Err(format!("{} must follow --spot-light", name)),
+    }
+}
+
+// Filter of a light casting shadows, None for the lights without shadows
+fn light_shadow_filter(light: &mut Light) -> Option<&mut Option<ShadowFilter>> {
+    match light {
+        Light::Directional(light) => Some(&mut light.shadow_filter),
+        Light::Point(light) => Some(&mut light.shadow_filter),
+        Light::Spot(light) => Some(&mut light.shadow_filter),
+        Light::Ambient { .. } | Light::Hemisphere { .. } => None,
+    }
+}
+
+fn parse_shadow_filter(name: &str, parameters: &ShadowFilterParameters) -> Result<ShadowFilter, String> {
+    match name {
+        "hard" => Ok(ShadowFilter::Hard),
+        "pcf" => Ok(ShadowFilter::Pcf { kernel: parameters.pcf_kernel }),
+        "poisson" => Ok(ShadowFilter::Poisson { radius: parameters.poisson_radius }),
+        "pcss" => Ok(ShadowFilter::Pcss { light_size: parameters.light_size }),
+        "vsm" => Ok(ShadowFilter::Variance {
+            blur_radius: parameters.blur_radius,
+            light_bleeding: parameters.light_bleeding,
+        }),
+        "esm" => Ok(ShadowFilter::Exponential {
+            blur_radius: parameters.blur_radius,
+            exponent: parameters.esm_exponent,
+        }),
+        _ => Err(format!("Unknown shadow filter {}", name)),
+    }
+}
+
+fn parse_range(name: &str, value: Option<String>) -> Result<f32, String> {
+    let range: f32 = parse_value(name, value)?;
+    if range.is_nan() || range <= 0.0 {
+        return Err(format!("Invalid light range {}", range));
+    }
+    Ok(range)
+}
+
+fn parse_blend_mode(name: &str, value: Option<String>) -> Result<BlendMode, String> {
+    let value: String = parse_value(name, value)?;
+    match value.as_str() {
+        "replace" => Ok(BlendMode::Replace),
+        "alpha" => Ok(BlendMode::Alpha),
+        "premultiplied" => Ok(BlendMode::Premultiplied),
+        "additive" => Ok(BlendMode::Additive),
+        "multiply" => Ok(BlendMode::Multiply),
+        _ => Err(format!("Unknown blend mode {}", value)),
+    }
+}
+
+fn parse_cull_mode(name: &str, value: Option<String>) -> Result<CullMode, String> {
+    let value: String = parse_value(name, value)?;
+    match value.as_str() {
+        "none" => Ok(CullMode::None),
+        "back" => Ok(CullMode::Back),
+        "front" => Ok(CullMode::Front),
+        _ => Err(format!("Unknown cull mode {}", value)),
+    }
+}
+
+fn parse_front_face(name: &str, value: Option<String>) -> Result<FrontFace, String> {
+    let value: String = parse_value(name, value)?;
+    match value.as_str() {
+        "cw" => Ok(FrontFace::Clockwise),
+        "ccw" => Ok(FrontFace::CounterClockwise),
+        _ => Err(format!("Unknown front face winding {}", value)),
+    }
+}
+
+fn parse_filter(name: &str, value: Option<String>) -> Result<Filter, String> {
+    let value: String = parse_value(name, value)?;
+    match value.as_str() {
+        "box" => Ok(Filter::Box),
+        "tent" => Ok(Filter::Tent),
+        "mitchell" => Ok(Filter::Mitchell),
+        "lanczos" => Ok(Filter::Lanczos),
+        _ => Err(format!("Unknown filter {}", value)),
+    }
+}
+
+fn parse_render_mode(name: &str, value: Option<String>) -> Result<RenderMode, String> {
+    let value: String = parse_value(name, value)?;
+    match value.as_str() {
+        "shaded" => Ok(RenderMode::Shaded),
+        "wireframe" => Ok(RenderMode::Wireframe),
+        "overlay" => Ok(RenderMode::Overlay),
+        _ => Err(format!("Unknown render mode {}", value)),
+    }
+}
+
+fn parse_shading(name: &str, value: Option<String>) -> Result<ShadingModel, String> {
+    let value: String = parse_value(name, value)?;
+    match value.as_str() {
+        "phong" => Ok(ShadingModel::Phong),
+        "pbr" => Ok(ShadingModel::Pbr),
+        _ => Err(format!("Unknown shading model {}", value)),
+    }
+}
+
+fn parse_positive(name: &str, value: Option<String>) -> Result<f32, String> {
+    let v: f32 = parse_value(name, value)?;
+    if !v.is_finite() || v <= 0.0 {
+        return Err(format!("{} must be positive, got {}", name, v));
+    }
+    Ok(v)
+}
+
+fn parse_unit(name: &str, value: Option<String>) -> Result<f32, String> {
+    let v: f32 = parse_value(name, value)?;
+    if !(0.0..=1.0).contains(&v) {
+        return Err(format!("{} must be in [0, 1], got {}", name, v));
+    }
+    Ok(v)
+}
+
+impl RenderConfig {
+    pub fn from_args<I: Iterator<Item = String>>(mut args: I) -> Result<RenderConfig, String> {
+        let mut config = RenderConfig::default();
+
+        let mut tonemap = String::from("clamp");
+        let mut white = None;
+        let mut gamma = 2.2;
+        // The filters are built once all of their parameters are known
+        let mut shadow_filter = String::from("hard");
+        let mut light_shadow_filters: Vec<(usize, String)> = Vec::new();
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--tonemap" => tonemap = parse_value(&arg, args.next())?,
+                "--exposure" => config.tone_mapping.exposure = parse_value(&arg, args.next())?,
+                "--white" => white = Some(parse_positive(&arg, args.next())?),
+                "--gamma" => gamma = parse_positive(&arg, args.next())?,
+                "--background" => config.background = parse_vec4f(&arg, args.next())?,
+                "--blend" => config.blend_mode = parse_blend_mode(&arg, args.next())?,
+                "--alpha-cutoff" => config.alpha_cutoff = Some(parse_value(&arg, args.next())?),
+                "--oit" => config.order_independent = true,
+                "--cull" => config.rasterizer.cull_mode = parse_cull_mode(&arg, args.next())?,
+                "--front-face" => config.rasterizer.front_face = parse_front_face(&arg, args.next())?,
+                "--two-sided" => config.two_sided = true,
+                "--msaa" => {
+                    config.msaa_samples = parse_value(&arg, args.next())?;
+                    if ![1, 2, 4, 8].contains(&config.msaa_samples) {
+                        return Err(format!("Unsupported sample count {}", config.msaa_samples));
+                    }
+                }
+                "--supersample" => {
+                    config.supersampling = parse_value(&arg, args.next())?;
+                    if config.supersampling == 0 {
+                        return Err(String::from("Supersampling scale must be at least 1"));
+                    }
+                }
+                "--filter" => config.resample_filter = parse_filter(&arg, args.next())?,
+                "--render-mode" => config.render_mode = parse_render_mode(&arg, args.next())?,
+                "--line-color" => config.line_color = parse_vec4f(&arg, args.next())?,
+                "--line-width" => {
+                    config.line_width = parse_value(&arg, args.next())?;
+                    if config.line_width.is_nan() || config.line_width <= 0.0 {
+                        return Err(format!("Invalid line width {}", config.line_width));
+                    }
+                }
+                "--label" => config.label = Some(parse_value(&arg, args.next())?),
+                "--stats" => config.stats = true,
+                "--label-scale" => {
+                    config.label_scale = parse_value(&arg, args.next())?;
+                    if config.label_scale == 0 {
+                        return Err(String::from("Label scale must be at least 1"));
+                    }
+                }
+                "--shadow-resolution" => {
+                    config.shadow.resolution = parse_value(&arg, args.next())?;
+                    if config.shadow.resolution == 0 {
+                        return Err(String::from("Shadow map resolution must be at least 1"));
+                    }
+                }
+                "--shadow-bias" => config.shadow.depth_bias = parse_value(&arg, args.next())?,
+                "--shadow-slope-bias" => config.shadow.slope_scaled_depth_bias = parse_value(&arg, args.next())?,
+                "--shadow-filter" => shadow_filter = parse_value(&arg, args.next())?,
+                "--pcf-kernel" => {
+                    config.shadow.filter_parameters.pcf_kernel = parse_value(&arg, args.next())?;
+                    if config.shadow.filter_parameters.pcf_kernel == 0 {
+                        return Err(String::from("PCF kernel size must be at least 1"));
+                    }
+                }
+                "--poisson-radius" => config.shadow.filter_parameters.poisson_radius = parse_positive(&arg, args.next())?,
+                "--light-size" => config.shadow.filter_parameters.light_size = parse_positive(&arg, args.next())?,
+                "--shadow-blur" => {
+                    config.shadow.filter_parameters.blur_radius = parse_value(&arg, args.next())?;
+                    if config.shadow.filter_parameters.blur_radius == 0 {
+                        return Err(String::from("Shadow blur radius must be at least 1"));
+                    }
+                }
+                "--light-bleeding" => {
+                    let light_bleeding = parse_value(&arg, args.next())?;
+                    if !(0.0..1.0).contains(&light_bleeding) {
+                        return Err(format!("Light bleeding reduction must be in [0, 1), got {}", light_bleeding));
+                    }
+                    config.shadow.filter_parameters.light_bleeding = light_bleeding;
+                }
+                "--esm-exponent" => config.shadow.filter_parameters.esm_exponent = parse_positive(&arg, args.next())?,
+                "--cascades" => {
+                    config.shadow.cascades = parse_value(&arg, args.next())?;
+                    if config.shadow.cascades == 0 {
+                        return Err(String::from("At least one shadow cascade is needed"));
+                    }
+                }
+                "--cascade-lambda" => {
+                    config.shadow.split_lambda = parse_value(&arg, args.next())?;
+                    if !(0.0..=1.0).contains(&config.shadow.split_lambda) {
+                        return Err(format!("Cascade split lambda must be in [0, 1], got {}", config.shadow.split_lambda));
+                    }
+                }
+                "--cascade-blend" => {
+                    config.shadow.cascade_blend = parse_value(&arg, args.next())?;
+                    if !(0.0..=1.0).contains(&config.shadow.cascade_blend) {
+                        return Err(format!("Cascade blend must be in [0, 1], got {}", config.shadow.cascade_blend));
+                    }
+                }
+                "--compare-shadows" => config.compare_shadows = true,
+                "--no-lights" => {
+                    config.lights.clear();
+                    light_shadow_filters.clear();
+                }
+                "--directional-light" => {
+                    // Direction towards the light
+                    let direction = parse_vec3f(&arg, args.next())?;
+                    if direction.length() == 0.0 {
+                        return Err(String::from("Directional light direction can't be zero"));
+                    }
+                    config.lights.push(Light::Directional(DirectionalLight::new(direction)));
+                }
+                "--point-light" => config.lights.push(Light::Point(PointLight::new(parse_vec3f(&arg, args.next())?))),
+                "--spot-light" => {
+                    // Aimed at the origin until a target is given
+                    let position = parse_vec3f(&arg, args.next())?;
+                    let direction = if position.length() > 0.0 { -position } else { Vec3f::new(0.0, -1.0, 0.0) };
+                    config.lights.push(Light::Spot(SpotLight::new(position, direction)));
+                }
+                "--ambient-light" => {
+                    let color = parse_vec3f(&arg, args.next())?;
+                    config.lights.push(Light::Ambient { color, intensity: 1.0 });
+                }
+                "--hemisphere-light" => {
+                    // Sky color then ground color
+                    let c = parse_floats(&arg, args.next(), 6)?;
+                    config.lights.push(Light::Hemisphere {
+                        color: Vec3f::new(c[0], c[1], c[2]),
+                        ground_color: Vec3f::new(c[3], c[4], c[5]),
+                        intensity: 1.0,
+                    });
+                }
+                "--light-color" => *last_light(&mut config, &arg)?.color_mut() = parse_vec3f(&arg, args.next())?,
+                "--light-intensity" => *last_light(&mut config, &arg)?.intensity_mut() = parse_value(&arg, args.next())?,
+                "--light-range" => {
+                    let range = parse_range(&arg, args.next())?;
+                    match last_light(&mut config, &arg)? {
+                        Light::Point(light) => light.range = range,
+                        Light::Spot(light) => light.range = range,
+                        _ => return Err(format!("{} must follow --point-light or --spot-light", arg)),
+                    }
+                }
+                "--light-shadow-filter" => {
+                    let name = parse_value(&arg, args.next())?;
+                    if light_shadow_filter(last_light(&mut config, &arg)?).is_none() {
+                        return Err(format!("{} must follow a directional, point or spot light", arg));
+                    }
+                    light_shadow_filters.push((config.lights.len() - 1, name));
+                }
+                "--spot-light-target" => {
+                    let target = parse_vec3f(&arg, args.next())?;
+                    let light = last_spot_light(&mut config, &arg)?;
+                    if target == light.position {
+                        return Err(String::from("Spot light target is the light position"));
+                    }
+                    light.direction = (target - light.position).normalized();
+                }
+                "--spot-light-cone" => {
+                    // Inner and outer half angles, in degrees
+                    let angles = parse_floats(&arg, args.next(), 2)?;
+                    if !(angles[0] >= 0.0 && angles[0] <= angles[1] && angles[1] < 90.0) {
+                        return Err(format!("Spot light cone must be 0 <= inner <= outer < 90, got {:?}", angles));
+                    }
+                    let light = last_spot_light(&mut config, &arg)?;
+                    light.inner_angle = angles[0].to_radians();
+                    light.outer_angle = angles[1].to_radians();
+                }
+                "--environment" => config.environment = Some(parse_value(&arg, args.next())?),
+                "--environment-intensity" => config.environment_intensity = parse_value(&arg, args.next())?,
+                "--sky-environment" => config.sky = Some(SkySettings::Environment),
+                "--sky-map" => config.sky = Some(SkySettings::Map(parse_value(&arg, args.next())?)),
+                "--sky-cube" => {
+                    // Faces +x, -x, +y, -y, +z, -z
+                    let value: String = parse_value(&arg, args.next())?;
+                    let faces: Vec<String> = value.split(',').map(|f| String::from(f.trim())).collect();
+                    if faces.len() != 6 {
+                        return Err(format!("Expected 6 faces for {}, got '{}'", arg, value));
+                    }
+                    config.sky = Some(SkySettings::Cube(faces));
+                }
+                "--sky-gradient" => {
+                    // Zenith, horizon then ground colors
+                    let c = parse_floats(&arg, args.next(), 9)?;
+                    config.sky = Some(SkySettings::Gradient {
+                        zenith: Vec3f::new(c[0], c[1], c[2]),
+                        horizon: Vec3f::new(c[3], c[4], c[5]),
+                        ground: Vec3f::new(c[6], c[7], c[8]),
+                    });
+                }
+                "--shading" => config.shading = parse_shading(&arg, args.next())?,
+                "--metallic" => config.material.metallic = parse_unit(&arg, args.next())?,
+                "--roughness" => config.material.roughness = parse_unit(&arg, args.next())?,
+                "--emissive" => config.material.emissive = parse_vec3f(&arg, args.next())?,
+                "--metallic-map" => config.material.metallic_map = Some(parse_value(&arg, args.next())?),
+                "--roughness-map" => config.material.roughness_map = Some(parse_value(&arg, args.next())?),
+                "--occlusion-map" => config.material.occlusion_map = Some(parse_value(&arg, args.next())?),
+                "--emissive-map" => config.material.emissive_map = Some(parse_value(&arg, args.next())?),
+                "--ssao" => config.ssao.enabled = true,
+                "--ssao-radius" => {
+                    config.ssao.radius = parse_value(&arg, args.next())?;
+                    if config.ssao.radius <= 0.0 {
+                        return Err(String::from("SSAO radius must be positive"));
+                    }
+                }
+                "--ssao-samples" => {
+                    config.ssao.samples = parse_value(&arg, args.next())?;
+                    if config.ssao.samples == 0 {
+                        return Err(String::from("At least one SSAO sample is needed"));
+                    }
+                }
+                "--ssao-bias" => config.ssao.bias = parse_value(&arg, args.next())?,
+                "--ssao-intensity" => config.ssao.intensity = parse_value(&arg, args.next())?,
+                "--ssao-blur" => config.ssao.blur_radius = parse_value(&arg, args.next())?,
+                "--ssao-debug" => {
+                    config.ssao.enabled = true;
+                    config.ssao.debug = true;
+                }
+                "--deferred" => config.deferred = true,
+                "--spot-light-cookie" => last_spot_light(&mut config, &arg)?.cookie = Some(parse_value(&arg, args.next())?),
+                _ => return Err(format!("Unknown argument {}", arg)),
+            }
+        }
+
+        config.tone_mapping.operator = match tonemap.as_str() {
+            "clamp" => ToneMapOperator::Clamp,
+            "gamma" => ToneMapOperator::Gamma { gamma },
+            "reinhard" => ToneMapOperator::Reinhard { white: white.unwrap_or(4.0) },
+            "aces" => ToneMapOperator::Aces,
+            "hable" => ToneMapOperator::Hable { white: white.unwrap_or(11.2) },
+            _ => return Err(format!("Unknown tone-mapping operator {}", tonemap)),
+        };
+
+        config.shadow.filter = parse_shadow_filter(&shadow_filter, &config.shadow.filter_parameters)?;
+        for (index, name) in light_shadow_filters {
+            let filter = parse_shadow_filter(&name, &config.shadow.filter_parameters)?;
+            if let Some(light_filter) = light_shadow_filter(&mut config.lights[index]) {
+                *light_filter = Some(filter);
+            }
+        }
+
+        for light in config.lights.iter() {
+            let filter = match light {
+                Light::Point(light) => light.shadow_filter,
+                Light::Spot(light) => light.shadow_filter,
+                _ => continue,
+            };
+            if filter.unwrap_or(config.shadow.filter).needs_orthographic_map() {
+                return Err(String::from(
+                    "PCSS, VSM and ESM shadows need a directional light, give point and spot lights another --light-shadow-filter",
+                ));
+            }
+        }
+
+        // The G-buffer only keeps the closest surface
+        if config.deferred && (config.blend_mode != BlendMode::Replace || config.order_independent) {
+            return Err(String::from("Deferred shading only draws opaque meshes"));
+        }
+
+        if config.sky == Some(SkySettings::Environment) && config.environment.is_none() {
+            return Err(String::from("--sky-environment needs an --environment map"));
+        }
+
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<RenderConfig, String> {
+        RenderConfig::from_args(args.iter().map(|s| s.to_string()))
+    }
+
+    #[test]
+    fn parse_tonemap_test() {
+        let config = parse(&["--tonemap", "reinhard", "--white", "2.0", "--exposure", "0.5"]).unwrap();
+        match config.tone_mapping.operator {
+            ToneMapOperator::Reinhard { white } => assert_eq!(white, 2.0),
+            _ => panic!("Wrong operator"),
+        }
+        assert_eq!(config.tone_mapping.exposure, 0.5);
+
+        assert!(parse(&["--tonemap", "unknown"]).is_err());
+        assert!(parse(&["--exposure"]).is_err());
+        assert!(parse(&["--exposure", "abc"]).is_err());
+        assert!(parse(&["--tonemap", "reinhard", "--white", "0"]).is_err());
+        assert!(parse(&["--tonemap", "gamma", "--gamma", "-2.2"]).is_err());
+        assert!(parse(&["--gamma", "NaN"]).is_err());
+    }
+
+    #[test]
+    fn parse_blend_test() {
+        let config = parse(&["--background", "0.5, 0.25,0,0", "--blend", "additive"]).unwrap();
+        assert_eq!(config.background.x, 0.5);
+        assert_eq!(config.background.y, 0.25);
+        assert_eq!(config.background.w, 0.0);
+        assert_eq!(config.blend_mode, BlendMode::Additive);
+
+        assert!(parse(&["--background", "0,0,0"]).is_err());
+        assert!(parse(&["--blend", "screen"]).is_err());
+    }
+
+    #[test]
+    fn parse_wireframe_test() {
+        let config = parse(&["--render-mode", "overlay", "--line-color", "0,0,0,0.5", "--line-width", "2"]).unwrap();
+        assert_eq!(config.render_mode, RenderMode::Overlay);
+        assert_eq!(config.line_color.w, 0.5);
+        assert_eq!(config.line_width, 2.0);
+
+        assert!(parse(&["--render-mode", "points"]).is_err());
+        assert!(parse(&["--line-width", "0"]).is_err());
+    }
+
+    #[test]
+    fn parse_shadow_test() {
+        let config = parse(&["--shadow-filter", "pcf", "--pcf-kernel", "5", "--shadow-bias", "1.5"]).unwrap();
+        assert_eq!(config.shadow.filter, ShadowFilter::Pcf { kernel: 5 });
+        assert_eq!(config.shadow.depth_bias, 1.5);
+
+        let config = parse(&["--light-size", "0.1", "--shadow-filter", "pcss"]).unwrap();
+        assert_eq!(config.shadow.filter, ShadowFilter::Pcss { light_size: 0.1 });
+
+        let config = parse(&["--shadow-filter", "vsm", "--shadow-blur", "4", "--light-bleeding", "0.5"]).unwrap();
+        assert_eq!(config.shadow.filter, ShadowFilter::Variance { blur_radius: 4, light_bleeding: 0.5 });
+
+        assert!(parse(&["--shadow-filter", "blur"]).is_err());
+        assert!(parse(&["--light-bleeding", "1"]).is_err());
+        assert!(parse(&["--pcf-kernel", "0"]).is_err());
+        assert!(parse(&["--poisson-radius", "NaN"]).is_err());
+        assert!(parse(&["--poisson-radius", "inf"]).is_err());
+        assert!(parse(&["--light-size", "-0.1"]).is_err());
+        assert!(parse(&["--esm-exponent", "-40"]).is_err());
+        assert!(parse(&["--esm-exponent", "NaN"]).is_err());
+        assert!(parse(&["--shadow-blur", "0"]).is_err());
+        assert!(parse(&["--shadow-blur", "-2"]).is_err());
+
+        let config = parse(&["--cascades", "4", "--cascade-lambda", "0.5"]).unwrap();
+        assert_eq!(config.shadow.cascades, 4);
+        assert_eq!(config.shadow.split_lambda, 0.5);
+        assert!(parse(&["--cascades", "0"]).is_err());
+    }
+
+    #[test]
+    fn parse_label_test() {
+        let config = parse(&["--label", "head", "--stats", "--label-scale", "2"]).unwrap();
+        assert_eq!(config.label.as_deref(), Some("head"));
+        assert!(config.stats);
+        assert_eq!(config.label_scale, 2);
+
+        assert!(parse(&["--label"]).is_err());
+        assert!(parse(&["--label-scale", "0"]).is_err());
+    }
+
+    #[test]
+    fn parse_lights_test() {
+        let config = parse(&[
+            "--no-lights",
+            "--point-light", "0,1,2", "--light-intensity", "3", "--light-shadow-filter", "pcf",
+            "--point-light", "1,1,1", "--light-color", "1,0.5,0", "--light-range", "10", "--light-shadow-filter", "poisson",
+            "--hemisphere-light", "0.2,0.3,0.5,0.1,0.1,0.0",
+            "--environment", "sky.hdr", "--environment-intensity", "0.5",
+            "--pcf-kernel", "5",
+        ])
+        .unwrap();
+        assert_eq!(config.lights.len(), 3);
+        let mut light = PointLight::new(Vec3f::new(0.0, 1.0, 2.0));
+        light.intensity = 3.0;
+        light.shadow_filter = Some(ShadowFilter::Pcf { kernel: 5 });
+        assert_eq!(config.lights[0], Light::Point(light));
+        match config.lights[1] {
+            Light::Point(light) => {
+                assert_eq!(light.color, Vec3f::new(1.0, 0.5, 0.0));
+                assert_eq!(light.range, 10.0);
+                assert_eq!(light.shadow_filter, Some(ShadowFilter::Poisson { radius: 2.0 }));
+            }
+            _ => panic!("Wrong light"),
+        }
+        match config.lights[2] {
+            Light::Hemisphere { ground_color, .. } => assert_eq!(ground_color, Vec3f::new(0.1, 0.1, 0.0)),
+            _ => panic!("Wrong light"),
+        }
+        assert_eq!(config.environment.as_deref(), Some("sky.hdr"));
+        assert_eq!(config.environment_intensity, 0.5);
+
+        assert_eq!(parse(&[]).unwrap().lights.len(), 2);
+        assert!(parse(&["--no-lights", "--light-intensity", "2"]).is_err());
+        assert!(parse(&["--ambient-light", "1,1,1", "--light-range", "2"]).is_err());
+        assert!(parse(&["--ambient-light", "1,1,1", "--light-shadow-filter", "pcf"]).is_err());
+        assert!(parse(&["--point-light", "0,1,2", "--light-shadow-filter", "blur"]).is_err());
+        assert!(parse(&["--point-light", "0,1,2", "--light-shadow-filter", "pcf", "--no-lights"]).unwrap().lights.is_empty());
+        assert!(parse(&["--point-light", "0,1,2", "--light-shadow-filter", "vsm"]).is_err());
+        assert!(parse(&["--point-light", "0,1,2", "--shadow-filter", "pcss"]).is_err());
+        assert!(parse(&["--point-light", "0,1,2", "--light-shadow-filter", "pcf", "--shadow-filter", "esm"]).is_ok());
+        assert!(parse(&["--point-light", "0,1"]).is_err());
+    }
+
+    #[test]
+    fn parse_spot_light_test() {
+        let config = parse(&[
+            "--no-lights",
+            "--spot-light", "0,2,0", "--spot-light-cone", "15,25",
+            "--spot-light", "1,1,1", "--spot-light-target", "1,0,1", "--spot-light-cookie", "gobo.tga",
+        ])
+        .unwrap();
+        let spot = |i: usize| match config.lights[i] {
+            Light::Spot(ref light) => light.clone(),
+            _ => panic!("Wrong light"),
+        };
+        assert_eq!(spot(0).direction, Vec3f::new(0.0, -1.0, 0.0));
+        assert!((spot(0).outer_angle - 25_f32.to_radians()).abs() < 1e-6);
+        assert_eq!(spot(1).direction, Vec3f::new(0.0, -1.0, 0.0));
+        assert_eq!(spot(1).cookie.as_deref(), Some("gobo.tga"));
+
+        assert!(parse(&["--spot-light", "0,2,0", "--spot-light-cone", "30,20"]).is_err());
+        assert!(parse(&["--spot-light", "0,2,0", "--spot-light-target", "0,2,0"]).is_err());
+        assert!(parse(&["--point-light", "0,2,0", "--spot-light-cone", "10,20"]).is_err());
+        assert!(parse(&["--spot-light", "0,2,0", "--light-shadow-filter", "pcss"]).is_err());
+        assert!(parse(&["--spot-light", "0,2,0", "--shadow-filter", "esm"]).is_err());
+        assert!(parse(&["--spot-light", "0,2,0", "--light-shadow-filter", "poisson", "--shadow-filter", "vsm"]).is_ok());
+    }
+
+    #[test]
+    fn parse_sky_test() {
+        let config = parse(&["--sky-cube", "px.tga, nx.tga,py.tga,ny.tga,pz.tga,nz.tga"]).unwrap();
+        match config.sky {
+            Some(SkySettings::Cube(ref faces)) => assert_eq!(faces[1], "nx.tga"),
+            _ => panic!("Wrong sky"),
+        }
+        let config = parse(&["--sky-gradient", "0.2,0.4,1,0.8,0.9,1,0.2,0.2,0.2"]).unwrap();
+        match config.sky {
+            Some(SkySettings::Gradient { horizon, .. }) => assert_eq!(horizon, Vec3f::new(0.8, 0.9, 1.0)),
+            _ => panic!("Wrong sky"),
+        }
+        assert!(parse(&["--environment", "sky.hdr", "--sky-environment"]).is_ok());
+
+        assert!(parse(&["--sky-environment"]).is_err());
+        assert!(parse(&["--sky-cube", "px.tga,nx.tga"]).is_err());
+        assert!(parse(&["--sky-gradient", "1,1,1"]).is_err());
+    }
+
+    #[test]
+    fn parse_material_test() {
+        let config = parse(&["--shading", "pbr", "--metallic", "1", "--roughness", "0.25", "--occlusion-map", "ao.tga"]).unwrap();
+        assert_eq!(config.shading, ShadingModel::Pbr);
+        assert_eq!(config.material.metallic, 1.0);
+        assert_eq!(config.material.roughness, 0.25);
+        assert_eq!(config.material.occlusion_map.as_deref(), Some("ao.tga"));
+
+        assert!(parse(&["--roughness", "2"]).is_err());
+        assert!(parse(&["--shading", "toon"]).is_err());
+    }
+
+    #[test]
+    fn parse_ssao_test() {
+        assert!(!parse(&[]).unwrap().ssao.enabled);
+        let config = parse(&["--ssao", "--ssao-radius", "0.5", "--ssao-samples", "32", "--ssao-blur", "0"]).unwrap();
+        assert!(config.ssao.enabled && !config.ssao.debug);
+        assert_eq!(config.ssao.radius, 0.5);
+        assert_eq!(config.ssao.samples, 32);
+        assert_eq!(config.ssao.blur_radius, 0);
+        assert!(parse(&["--ssao-debug"]).unwrap().ssao.enabled);
+
+        assert!(parse(&["--ssao-radius", "0"]).is_err());
+        assert!(parse(&["--ssao-samples", "0"]).is_err());
+    }
+
+    #[test]
+    fn parse_deferred_test() {
+        assert!(!parse(&[]).unwrap().deferred);
+        assert!(parse(&["--deferred", "--shading", "pbr", "--alpha-cutoff", "0.5"]).unwrap().deferred);
+        assert!(parse(&["--deferred", "--blend", "alpha"]).is_err());
+        assert!(parse(&["--deferred", "--oit"]).is_err());
+    }
+}
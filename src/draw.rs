@@ -0,0 +1,432 @@
+use color::{self, BlendMode};
+use math::{Vec2f, Vec4f};
+use ppm::{Image, RGB};
+
+// 2D primitives drawn directly on an image, for debug overlays.
+// Pixel (x, y) covers [x, x + 1] x [y, y + 1], y going up like `Image::set`.
+// Everything is clipped to the image bounds and alpha blended in linear space,
+// the alpha of the color is multiplied by the coverage of the pixel.
+
+fn fpart(x: f32) -> f32 {
+    x - x.floor()
+}
+
+fn rfpart(x: f32) -> f32 {
+    1.0 - fpart(x)
+}
+
+// Distance from p to the segment [a, b]
+fn segment_distance(p: Vec2f, a: Vec2f, b: Vec2f) -> f32 {
+    let (ab, ap) = (b - a, p - a);
+    let length2 = ab.dot(ab);
+    let t = if length2 > 0.0 { (ap.dot(ab) / length2).clamp(0.0, 1.0) } else { 0.0 };
+    (ap - ab * t).length()
+}
+
+// Clips the segment [p0, p1] to the box [min, max] (Liang-Barsky), None when
+// it is outside or not finite. The clipping is done in double precision, and
+// the clipped endpoints are clamped to the box, so that far away endpoints
+// still give the visible part of the segment.
+fn clip_segment(p0: Vec2f, p1: Vec2f, min: Vec2f, max: Vec2f) -> Option<(Vec2f, Vec2f)> {
+    if [p0.x, p0.y, p1.x, p1.y].iter().any(|c| !c.is_finite()) {
+        return None;
+    }
+
+    let (x0, y0) = (f64::from(p0.x), f64::from(p0.y));
+    let (dx, dy) = (f64::from(p1.x) - x0, f64::from(p1.y) - y0);
+    let (mut t0, mut t1) = (0.0_f64, 1.0_f64);
+    let edges = [
+        (-dx, x0 - f64::from(min.x)),
+        (dx, f64::from(max.x) - x0),
+        (-dy, y0 - f64::from(min.y)),
+        (dy, f64::from(max.y) - y0),
+    ];
+    for &(p, q) in edges.iter() {
+        if p == 0.0 {
+            // Parallel to the edge, outside of it
+            if q < 0.0 {
+                return None;
+            }
+        } else if p < 0.0 {
+            t0 = t0.max(q / p);
+        } else {
+            t1 = t1.min(q / p);
+        }
+    }
+    if t0 > t1 {
+        return None;
+    }
+
+    let point = |t: f64| {
+        let (x, y) = ((x0 + dx * t) as f32, (y0 + dy * t) as f32);
+        Vec2f::new(x.clamp(min.x, max.x), y.clamp(min.y, max.y))
+    };
+    Some((point(t0), point(t1)))
+}
+
+impl Image {
+    // Blends `c` over the pixel, does nothing outside of the image
+    pub fn blend(&mut self, x: i32, y: i32, c: RGB, coverage: f32) {
+        if x < 0 || y < 0 || x >= self.width as i32 || y >= self.height as i32 || coverage <= 0.0 {
+            return;
+        }
+        let (x, y) = (x as usize, y as usize);
+        let src = color::decode_srgb8(c.r, c.g, c.b);
+        let alpha = f32::from(c.a) / 255.0 * coverage.min(1.0);
+        let dst = color::decode_srgba8(self.get(x, y));
+        let res = BlendMode::Alpha.blend(Vec4f::from_vec3f(src, alpha), dst);
+        self.set(x, y, color::encode_srgba8(res));
+    }
+
+    // Calls `coverage` for every pixel of the clipped box, with the pixel center
+    fn fill_coverage<F: Fn(Vec2f) -> f32>(&mut self, min: Vec2f, max: Vec2f, c: RGB, coverage: F) {
+        let xmin = (min.x.floor() as i32).max(0);
+        let ymin = (min.y.floor() as i32).max(0);
+        let xmax = (max.x.ceil() as i32).min(self.width as i32 - 1);
+        let ymax = (max.y.ceil() as i32).min(self.height as i32 - 1);
+        for y in ymin..=ymax {
+            for x in xmin..=xmax {
+                let center = Vec2f::new(x as f32 + 0.5, y as f32 + 0.5);
+                self.blend(x, y, c, coverage(center));
+            }
+        }
+    }
+
+    // Copies `src` with its bottom left corner at pixel (x, y), without blending
+    pub fn blit(&mut self, src: &Image, x: i32, y: i32) {
+        for sy in 0..src.height {
+            for sx in 0..src.width {
+                let (dx, dy) = (x + sx as i32, y + sy as i32);
+                if dx >= 0 && dy >= 0 && dx < self.width as i32 && dy < self.height as i32 {
+                    self.set(dx as usize, dy as usize, src.get(sx, sy));
+                }
+            }
+        }
+    }
+
+    // Aliased one pixel wide line, between pixels (x0, y0) and (x1, y1) included
+    #[allow(dead_code)]
+    pub fn draw_line(&mut self, x0: i32, y0: i32, x1: i32, y1: i32, c: RGB) {
+        // Cohen-Sutherland clipping to the pixels of the image, the clipped
+        // endpoints are rounded to the closest pixel
+        const LEFT: u8 = 1;
+        const RIGHT: u8 = 2;
+        const BOTTOM: u8 = 4;
+        const TOP: u8 = 8;
+        let (xmax, ymax) = (self.width as f64 - 1.0, self.height as f64 - 1.0);
+        let outcode = |x: f64, y: f64| {
+            let mut code = 0;
+            if x < 0.0 { code |= LEFT; } else if x > xmax { code |= RIGHT; }
+            if y < 0.0 { code |= BOTTOM; } else if y > ymax { code |= TOP; }
+            code
+        };
+
+        let (mut x0, mut y0, mut x1, mut y1) = (f64::from(x0), f64::from(y0), f64::from(x1), f64::from(y1));
+        let (mut code0, mut code1) = (outcode(x0, y0), outcode(x1, y1));
+        while code0 | code1 != 0 {
+            if code0 & code1 != 0 {
+                return;
+            }
+            // Moves an endpoint outside of the image onto the edge it crosses
+            let code = if code0 != 0 { code0 } else { code1 };
+            let (x, y) = if code & LEFT != 0 {
+                (0.0, y0 + (y1 - y0) * (0.0 - x0) / (x1 - x0))
+            } else if code & RIGHT != 0 {
+                (xmax, y0 + (y1 - y0) * (xmax - x0) / (x1 - x0))
+            } else if code & BOTTOM != 0 {
+                (x0 + (x1 - x0) * (0.0 - y0) / (y1 - y0), 0.0)
+            } else {
+                (x0 + (x1 - x0) * (ymax - y0) / (y1 - y0), ymax)
+            };
+            if code == code0 {
+                x0 = x;
+                y0 = y;
+                code0 = outcode(x0, y0);
+            } else {
+                x1 = x;
+                y1 = y;
+                code1 = outcode(x1, y1);
+            }
+        }
+        let (x0, y0, x1, y1) = (x0.round() as i32, y0.round() as i32, x1.round() as i32, y1.round() as i32);
+
+        let (dx, dy) = ((x1 - x0).abs(), -(y1 - y0).abs());
+        let (sx, sy) = (if x0 < x1 { 1 } else { -1 }, if y0 < y1 { 1 } else { -1 });
+        let (mut x, mut y, mut error) = (x0, y0, dx + dy);
+        loop {
+            self.blend(x, y, c, 1.0);
+            if x == x1 && y == y1 {
+                break;
+            }
+            let e2 = 2 * error;
+            if e2 >= dy {
+                error += dy;
+                x += sx;
+            }
+            if e2 <= dx {
+                error += dx;
+                y += sy;
+            }
+        }
+    }
+
+    // Xiaolin Wu anti-aliased one pixel wide line
+    pub fn draw_line_aa(&mut self, p0: Vec2f, p1: Vec2f, c: RGB) {
+        // Clipped with a one pixel margin, so that the partially covered
+        // endpoints stay off the image
+        let (min, max) = (Vec2f::new(-1.0, -1.0), Vec2f::new(self.width as f32 + 1.0, self.height as f32 + 1.0));
+        let (p0, p1) = match clip_segment(p0, p1, min, max) {
+            Some(segment) => segment,
+            None => return,
+        };
+
+        // Wu's algorithm works with pixel centers at integer coordinates
+        let (mut x0, mut y0, mut x1, mut y1) = (p0.x - 0.5, p0.y - 0.5, p1.x - 0.5, p1.y - 0.5);
+
+        let steep = (y1 - y0).abs() > (x1 - x0).abs();
+        if steep {
+            std::mem::swap(&mut x0, &mut y0);
+            std::mem::swap(&mut x1, &mut y1);
+        }
+        if x0 > x1 {
+            std::mem::swap(&mut x0, &mut x1);
+            std::mem::swap(&mut y0, &mut y1);
+        }
+
+        let plot = |image: &mut Image, x: i32, y: i32, coverage: f32| {
+            if steep {
+                image.blend(y, x, c, coverage);
+            } else {
+                image.blend(x, y, c, coverage);
+            }
+        };
+
+        let dx = x1 - x0;
+        let gradient = if dx == 0.0 { 1.0 } else { (y1 - y0) / dx };
+
+        // Endpoints are weighted by how much of their pixel the line covers
+        let xend = x0.round();
+        let yend = y0 + gradient * (xend - x0);
+        let xgap = rfpart(x0 + 0.5);
+        let xpx0 = xend as i32;
+        plot(self, xpx0, yend.floor() as i32, rfpart(yend) * xgap);
+        plot(self, xpx0, yend.floor() as i32 + 1, fpart(yend) * xgap);
+        let mut intery = yend + gradient;
+
+        let xend = x1.round();
+        let yend = y1 + gradient * (xend - x1);
+        let xgap = fpart(x1 + 0.5);
+        let xpx1 = xend as i32;
+        plot(self, xpx1, yend.floor() as i32, rfpart(yend) * xgap);
+        plot(self, xpx1, yend.floor() as i32 + 1, fpart(yend) * xgap);
+
+        for x in xpx0 + 1..xpx1 {
+            plot(self, x, intery.floor() as i32, rfpart(intery));
+            plot(self, x, intery.floor() as i32 + 1, fpart(intery));
+            intery += gradient;
+        }
+    }
+
+    // Anti-aliased line of any width, with round caps
+    #[allow(dead_code)]
+    pub fn draw_thick_line(&mut self, p0: Vec2f, p1: Vec2f, width: f32, c: RGB) {
+        let half_width = width * 0.5;
+        let min = Vec2f::new(p0.x.min(p1.x) - half_width - 1.0, p0.y.min(p1.y) - half_width - 1.0);
+        let max = Vec2f::new(p0.x.max(p1.x) + half_width, p0.y.max(p1.y) + half_width);
+        self.fill_coverage(min, max, c, |p| (half_width + 0.5 - segment_distance(p, p0, p1)).clamp(0.0, 1.0));
+    }
+
+    // Rectangle of pixels [x, x + width) x [y, y + height)
+    pub fn fill_rect(&mut self, x: i32, y: i32, width: i32, height: i32, c: RGB) {
+        let xmin = x.max(0);
+        let ymin = y.max(0);
+        let xmax = (x + width).min(self.width as i32);
+        let ymax = (y + height).min(self.height as i32);
+        for py in ymin..ymax {
+            for px in xmin..xmax {
+                self.blend(px, py, c, 1.0);
+            }
+        }
+    }
+
+    // One pixel wide outline of the same pixels as `fill_rect`
+    #[allow(dead_code)]
+    pub fn draw_rect(&mut self, x: i32, y: i32, width: i32, height: i32, c: RGB) {
+        if width <= 0 || height <= 0 {
+            return;
+        }
+        self.fill_rect(x, y, width, 1, c);
+        if height > 1 {
+            self.fill_rect(x, y + height - 1, width, 1, c);
+        }
+        if height > 2 {
+            self.fill_rect(x, y + 1, 1, height - 2, c);
+            if width > 1 {
+                self.fill_rect(x + width - 1, y + 1, 1, height - 2, c);
+            }
+        }
+    }
+
+    // Anti-aliased circle outline, one pixel wide
+    #[allow(dead_code)]
+    pub fn draw_circle(&mut self, center: Vec2f, radius: f32, c: RGB) {
+        let extent = Vec2f::new(radius + 1.0, radius + 1.0);
+        self.fill_coverage(center - extent, center + extent, c, |p| {
+            let distance = (p - center).length();
+            (1.0 - (distance - radius).abs()).clamp(0.0, 1.0)
+        });
+    }
+
+    // Anti-aliased disk
+    #[allow(dead_code)]
+    pub fn fill_circle(&mut self, center: Vec2f, radius: f32, c: RGB) {
+        let extent = Vec2f::new(radius + 1.0, radius + 1.0);
+        self.fill_coverage(center - extent, center + extent, c, |p| {
+            (radius + 0.5 - (p - center).length()).clamp(0.0, 1.0)
+        });
+    }
+
+    // Closed anti-aliased outline through the points
+    #[allow(dead_code)]
+    pub fn draw_polygon(&mut self, points: &[Vec2f], c: RGB) {
+        for (i, &p) in points.iter().enumerate() {
+            self.draw_line_aa(p, points[(i + 1) % points.len()], c);
+        }
+    }
+
+    // Fills the pixels whose center is inside the polygon, with the even-odd rule
+    #[allow(dead_code)]
+    pub fn fill_polygon(&mut self, points: &[Vec2f], c: RGB) {
+        if points.len() < 3 {
+            return;
+        }
+
+        let ymin = points.iter().fold(f32::MAX, |m, p| m.min(p.y));
+        let ymax = points.iter().fold(f32::MIN, |m, p| m.max(p.y));
+        let ymin = (ymin.floor() as i32).max(0);
+        let ymax = (ymax.ceil() as i32).min(self.height as i32 - 1);
+
+        let mut crossings = Vec::new();
+        for y in ymin..=ymax {
+            let sy = y as f32 + 0.5;
+            crossings.clear();
+            for (i, &a) in points.iter().enumerate() {
+                let b = points[(i + 1) % points.len()];
+                // Half open so that vertices shared by two edges count once
+                if (a.y <= sy) != (b.y <= sy) {
+                    crossings.push(a.x + (sy - a.y) / (b.y - a.y) * (b.x - a.x));
+                }
+            }
+            crossings.sort_by(|a, b| a.total_cmp(b));
+
+            for span in crossings.chunks(2) {
+                if span.len() < 2 {
+                    continue;
+                }
+                // Pixels with their center in [span[0], span[1])
+                let first = ((span[0] - 0.5).ceil() as i32).max(0);
+                let last = ((span[1] - 0.5).ceil() as i32).min(self.width as i32);
+                for x in first..last {
+                    self.blend(x, y, c, 1.0);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn count(image: &Image) -> usize {
+        let mut n = 0;
+        for y in 0..image.height {
+            for x in 0..image.width {
+                if image.get(x, y).r > 0 {
+                    n += 1;
+                }
+            }
+        }
+        n
+    }
+
+    #[test]
+    fn clipping_test() {
+        let mut image = Image::new(8, 8);
+        image.draw_line(-20, -3, 30, 12, RGB::white());
+        image.draw_line_aa(Vec2f::new(-100.0, 4.0), Vec2f::new(100.0, 5.0), RGB::white());
+        image.draw_line_aa(Vec2f::new(4.0, -100.0), Vec2f::new(5.0, 100.0), RGB::white());
+        image.draw_line_aa(Vec2f::new(-1e10, 2.5), Vec2f::new(1e10, 2.5), RGB::white());
+        image.draw_line_aa(Vec2f::new(-1e10, -1e10), Vec2f::new(-2e10, 1e10), RGB::white());
+        image.draw_line_aa(Vec2f::new(1e10, -1e10), Vec2f::new(-1e10, 1e10), RGB::white());
+        image.draw_line_aa(Vec2f::new(3.5, 1e10), Vec2f::new(3.5, 2e10), RGB::white());
+        image.draw_line_aa(Vec2f::new(f32::NEG_INFINITY, 2.5), Vec2f::new(f32::NAN, 2.5), RGB::white());
+        image.draw_line(i32::MIN, i32::MIN, i32::MAX, i32::MAX, RGB::white());
+        image.draw_line(i32::MIN, 5, i32::MIN + 10, 5, RGB::white());
+        image.draw_thick_line(Vec2f::new(-5.0, -5.0), Vec2f::new(20.0, 20.0), 3.0, RGB::white());
+        image.fill_circle(Vec2f::new(8.0, 8.0), 20.0, RGB::white());
+        image.fill_polygon(&[Vec2f::new(-5.0, -5.0), Vec2f::new(20.0, -5.0), Vec2f::new(0.0, 20.0)], RGB::white());
+        assert_eq!(count(&image), 64);
+
+        // Far away endpoints still draw the visible part of the line
+        let mut image = Image::new(8, 8);
+        image.draw_line_aa(Vec2f::new(-1e10, 2.5), Vec2f::new(1e10, 2.5), RGB::white());
+        image.draw_line(i32::MIN, 5, i32::MAX, 5, RGB::white());
+        assert_eq!(count(&image), 16);
+        assert!((0..8).all(|x| image.get(x, 2).r == 255 && image.get(x, 5).r == 255));
+    }
+
+    #[test]
+    fn rect_test() {
+        let mut image = Image::new(8, 8);
+        image.fill_rect(1, 2, 3, 4, RGB::white());
+        assert_eq!(count(&image), 12);
+        assert!(image.get(1, 2).r == 255 && image.get(3, 5).r == 255 && image.get(4, 5).r == 0);
+
+        let mut image = Image::new(8, 8);
+        image.draw_rect(1, 2, 3, 4, RGB::white());
+        assert_eq!(count(&image), 10);
+    }
+
+    #[test]
+    fn line_aa_test() {
+        // Horizontal line through the pixel centers only touches its own row
+        let mut image = Image::new(8, 8);
+        image.draw_line_aa(Vec2f::new(1.5, 3.5), Vec2f::new(6.5, 3.5), RGB::white());
+        assert_eq!(count(&image), 6);
+        assert_eq!(image.get(3, 3).r, 255);
+
+        // Half transparent color gives the same result as half coverage
+        let mut a = Image::new(1, 1);
+        a.blend(0, 0, RGB::new_rgba(255, 255, 255, 128), 1.0);
+        let mut b = Image::new(1, 1);
+        b.blend(0, 0, RGB::white(), 128.0 / 255.0);
+        assert_eq!(a.get(0, 0).g, b.get(0, 0).g);
+    }
+
+    #[test]
+    fn fill_polygon_test() {
+        let square = [Vec2f::new(1.0, 1.0), Vec2f::new(5.0, 1.0), Vec2f::new(5.0, 5.0), Vec2f::new(1.0, 5.0)];
+        let mut image = Image::new(8, 8);
+        image.fill_polygon(&square, RGB::white());
+        assert_eq!(count(&image), 16);
+
+        // Concave notch in the top edge
+        let notched = [
+            Vec2f::new(1.0, 1.0),
+            Vec2f::new(5.0, 1.0),
+            Vec2f::new(5.0, 5.0),
+            Vec2f::new(3.0, 3.0),
+            Vec2f::new(1.0, 5.0),
+        ];
+        let mut image = Image::new(8, 8);
+        image.fill_polygon(&notched, RGB::white());
+        assert!(count(&image) < 16);
+        assert_eq!(image.get(3, 4).r, 0);
+
+        // Non finite vertices don't stop the fill
+        let mut image = Image::new(8, 8);
+        image.fill_polygon(&[Vec2f::new(1.0, 1.0), Vec2f::new(f32::NAN, 3.0), Vec2f::new(1.0, 5.0)], RGB::white());
+        image.fill_polygon(&[Vec2f::new(1.0, 1.0), Vec2f::new(f32::INFINITY, 3.0), Vec2f::new(1.0, 5.0)], RGB::white());
+    }
+}
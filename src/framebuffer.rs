@@ -0,0 +1,111 @@
+use std::ops::{Add, Mul};
+
+// Generic render target, rows are stored bottom to top like the z-buffer,
+// ie. (0, 0) is the bottom left pixel.
+// Multisampled targets store `samples` values per pixel, `get` and `set`
+// then access the first sample of the pixel.
+
+#[derive(Debug, Clone)]
+pub struct Framebuffer<T> {
+    pub width: usize,
+    pub height: usize,
+    pub samples: usize,
+    data: Vec<T>,
+}
+
+impl<T: Copy> Framebuffer<T> {
+    pub fn new(width: usize, height: usize, clear_value: T) -> Framebuffer<T> {
+        Framebuffer::new_multisample(width, height, 1, clear_value)
+    }
+
+    pub fn new_multisample(width: usize, height: usize, samples: usize, clear_value: T) -> Framebuffer<T> {
+        Framebuffer {
+            width,
+            height,
+            samples,
+            data: vec![clear_value; width * height * samples],
+        }
+    }
+
+    // Copies every pixel to all the samples of a new multisampled target
+    pub fn multisampled(&self, samples: usize) -> Framebuffer<T> {
+        assert_eq!(self.samples, 1);
+        let mut data = Vec::with_capacity(self.data.len() * samples);
+        for &v in &self.data {
+            for _ in 0..samples {
+                data.push(v);
+            }
+        }
+        Framebuffer {
+            width: self.width,
+            height: self.height,
+            samples,
+            data,
+        }
+    }
+
+    #[inline(always)]
+    fn index(&self, x: usize, y: usize, s: usize) -> usize {
+        (y * self.width + x) * self.samples + s
+    }
+
+    #[allow(dead_code)]
+    #[inline(always)]
+    pub fn set(&mut self, x: usize, y: usize, v: T) {
+        let i = self.index(x, y, 0);
+        self.data[i] = v;
+    }
+
+    #[inline(always)]
+    pub fn get(&self, x: usize, y: usize) -> T {
+        self.data[self.index(x, y, 0)]
+    }
+
+    #[inline(always)]
+    pub fn get_mut(&mut self, x: usize, y: usize) -> &mut T {
+        self.get_sample_mut(x, y, 0)
+    }
+
+    #[inline(always)]
+    pub fn get_sample(&self, x: usize, y: usize, s: usize) -> T {
+        self.data[self.index(x, y, s)]
+    }
+
+    #[inline(always)]
+    pub fn get_sample_mut(&mut self, x: usize, y: usize, s: usize) -> &mut T {
+        let i = self.index(x, y, s);
+        &mut self.data[i]
+    }
+
+    pub fn clear(&mut self, v: T) {
+        for d in self.data.iter_mut() {
+            *d = v;
+        }
+    }
+
+    pub fn data(&self) -> &[T] {
+        &self.data
+    }
+}
+
+impl<T: Copy + Add<Output = T> + Mul<f32, Output = T>> Framebuffer<T> {
+    // Box filter of the samples of each pixel into a single sampled target
+    pub fn resolve(&self) -> Framebuffer<T> {
+        let weight = 1.0 / self.samples as f32;
+        let data = self
+            .data
+            .chunks(self.samples)
+            .map(|samples| {
+                let sum = samples[1..].iter().fold(samples[0], |acc, &s| acc + s);
+                sum * weight
+            })
+            .collect();
+
+        Framebuffer {
+            width: self.width,
+            height: self.height,
+            samples: 1,
+            data,
+        }
+    }
+}
@@ -0,0 +1,31 @@
+use framebuffer::Framebuffer;
+use math::{Vec3f, Vec4f};
+
+// Deferred shading: a geometry pass writes the surface seen through every
+// sample to a G-buffer, whose depth is the z-buffer of the pass, then a
+// lighting pass shades each covered sample once. The cost of the lights no
+// longer depends on the overdraw.
+
+// Attributes of the surface at a sample, shared by the shading models. The
+// fields a model doesn't use are left to their default.
+#[derive(Clone, Copy, Default)]
+pub struct GSample {
+    // World space
+    pub position: Vec3f,
+    // World space normal, after normal mapping
+    pub normal: Vec3f,
+    // Interpolated normal of the mesh, around which the Phong highlights are
+    // measured
+    pub vertex_normal: Vec3f,
+    // Linear color with straight alpha
+    pub albedo: Vec4f,
+    // Phong exponent, from the specular map
+    pub shininess: f32,
+    pub metallic: f32,
+    pub roughness: f32,
+    // Ambient occlusion, of the maps and of the screen space pass
+    pub occlusion: f32,
+    pub emissive: Vec3f,
+}
+
+pub type GBuffer = Framebuffer<GSample>;
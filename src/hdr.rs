@@ -0,0 +1,158 @@
+use framebuffer::Framebuffer;
+use math::Vec3f;
+use std::io::{Result, Write};
+
+// Radiance RGBE (.hdr) and Portable Float Map (.pfm) writers for
+// float framebuffers. Values are written as-is, in linear space, and
+// the alpha channel of RGBA framebuffers is dropped. Multisampled
+// framebuffers must be resolved first.
+
+fn rgbe_from_vec3f(c: Vec3f) -> [u8; 4] {
+    let v = c.x.max(c.y.max(c.z));
+    if v < 1e-32 {
+        return [0, 0, 0, 0];
+    }
+
+    // v = m * 2^e with m in [0.5, 1[
+    let mut e = v.log2().floor() as i32 + 1;
+    if v / 2_f32.powi(e) >= 1.0 {
+        e += 1;
+    }
+    let scale = 256.0 / 2_f32.powi(e);
+
+    let mantissa = |c: f32| (c.max(0.0) * scale).min(255.0) as u8;
+    [mantissa(c.x), mantissa(c.y), mantissa(c.z), (e + 128) as u8]
+}
+
+// Run length encoding of one component of a scanline, as expected by
+// the "new" Radiance RLE format.
+fn write_rle_component<W: Write>(w: &mut W, data: &[u8]) -> Result<()> {
+    const MIN_RUN: usize = 4;
+    const MAX_LEN: usize = 127;
+
+    let mut cur = 0;
+    while cur < data.len() {
+        // Look for the next run
+        let mut run_start = cur;
+        let mut run_len = 0;
+        while run_start < data.len() {
+            run_len = 1;
+            while run_start + run_len < data.len()
+                && run_len < MAX_LEN
+                && data[run_start + run_len] == data[run_start]
+            {
+                run_len += 1;
+            }
+            if run_len >= MIN_RUN {
+                break;
+            }
+            run_start += run_len;
+        }
+
+        // Dump the literals up to the run
+        while cur < run_start {
+            let n = (run_start - cur).min(MAX_LEN + 1);
+            w.write_all(&[n as u8])?;
+            w.write_all(&data[cur..cur + n])?;
+            cur += n;
+        }
+
+        if run_len >= MIN_RUN && run_start < data.len() {
+            w.write_all(&[128 + run_len as u8, data[run_start]])?;
+            cur += run_len;
+        }
+    }
+
+    Ok(())
+}
+
+pub fn write_radiance<W: Write, T: Copy + Into<Vec3f>>(w: &mut W, fb: &Framebuffer<T>) -> Result<()> {
+    write!(w, "#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y {} +X {}\n", fb.height, fb.width)?;
+
+    let mut scanline = vec![[0_u8; 4]; fb.width];
+    let mut component = vec![0_u8; fb.width];
+
+    // Radiance images are stored top to bottom
+    for y in (0..fb.height).rev() {
+        for (x, rgbe) in scanline.iter_mut().enumerate() {
+            *rgbe = rgbe_from_vec3f(fb.get(x, y).into());
+        }
+
+        if fb.width < 8 || fb.width > 0x7fff {
+            // RLE is not allowed for those widths, write flat
+            for rgbe in &scanline {
+                w.write_all(rgbe)?;
+            }
+            continue;
+        }
+
+        w.write_all(&[2, 2, (fb.width >> 8) as u8, (fb.width & 0xff) as u8])?;
+        for c in 0..4 {
+            for (x, rgbe) in scanline.iter().enumerate() {
+                component[x] = rgbe[c];
+            }
+            write_rle_component(w, &component)?;
+        }
+    }
+
+    Ok(())
+}
+
+pub fn write_pfm<W: Write, T: Copy + Into<Vec3f>>(w: &mut W, fb: &Framebuffer<T>) -> Result<()> {
+    // Negative scale means little endian
+    write!(w, "PF\n{} {}\n-1.0\n", fb.width, fb.height)?;
+
+    // PFM scanlines are stored bottom to top, like the framebuffer
+    for &c in fb.data() {
+        let c: Vec3f = c.into();
+        w.write_all(&c.x.to_bits().to_le_bytes())?;
+        w.write_all(&c.y.to_bits().to_le_bytes())?;
+        w.write_all(&c.z.to_bits().to_le_bytes())?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vec3f_from_rgbe(rgbe: [u8; 4]) -> Vec3f {
+        if rgbe[3] == 0 {
+            return Vec3f::new(0.0, 0.0, 0.0);
+        }
+        let scale = 2_f32.powi(i32::from(rgbe[3]) - (128 + 8));
+        Vec3f::new(
+            (f32::from(rgbe[0]) + 0.5) * scale,
+            (f32::from(rgbe[1]) + 0.5) * scale,
+            (f32::from(rgbe[2]) + 0.5) * scale,
+        )
+    }
+
+    #[test]
+    fn rgbe_roundtrip_test() {
+        let values = [
+            Vec3f::new(1.0, 0.5, 0.25),
+            Vec3f::new(12.0, 3.0, 0.1),
+            Vec3f::new(0.001, 0.002, 0.003),
+        ];
+
+        for &v in values.iter() {
+            let res = vec3f_from_rgbe(rgbe_from_vec3f(v));
+            let max = v.x.max(v.y.max(v.z));
+            assert!((res.x - v.x).abs() <= max / 128.0);
+            assert!((res.y - v.y).abs() <= max / 128.0);
+            assert!((res.z - v.z).abs() <= max / 128.0);
+        }
+
+        assert_eq!(rgbe_from_vec3f(Vec3f::new(0.0, 0.0, 0.0)), [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn rle_component_test() {
+        let data = [1, 2, 3, 7, 7, 7, 7, 7, 4];
+        let mut out = Vec::new();
+        write_rle_component(&mut out, &data).unwrap();
+        assert_eq!(out, vec![3, 1, 2, 3, 128 + 5, 7, 1, 4]);
+    }
+}
@@ -0,0 +1,325 @@
+use framebuffer::Framebuffer;
+use math::{Vec2f, Vec3f, Vec4f};
+use pbr;
+use resample::{self, Filter};
+use stb_image::image;
+use std::f32::consts::PI;
+
+// Image based lighting from an equirectangular environment map: the diffuse
+// irradiance is projected on spherical harmonics, the specular reflections
+// are prefiltered for increasing roughnesses in a chain of lat-long maps, and
+// the BRDF integrated over the specular lobe is tabulated for the split sum
+// approximation.
+
+// Width of the first prefiltered specular level, which is the environment
+// itself. Every next level is half the size of the previous one and blurred
+// for a higher roughness.
+const SPECULAR_WIDTH: usize = 256;
+const SPECULAR_LEVELS: usize = 6;
+const SPECULAR_SAMPLES: usize = 64;
+
+const BRDF_LUT_SIZE: usize = 32;
+const BRDF_LUT_SAMPLES: usize = 256;
+
+// Latitude-longitude mapping, u turns around +y starting from -z and v goes
+// from the bottom (-y) to the top (+y) of the map
+pub fn direction_to_uv(d: Vec3f) -> Vec2f {
+    let d = d.normalized();
+    Vec2f::new(0.5 + d.x.atan2(-d.z) / (2.0 * PI), 0.5 + d.y.clamp(-1.0, 1.0).asin() / PI)
+}
+
+pub fn uv_to_direction(uv: Vec2f) -> Vec3f {
+    let phi = (uv.x - 0.5) * 2.0 * PI;
+    let latitude = (uv.y - 0.5) * PI;
+    Vec3f::new(phi.sin() * latitude.cos(), latitude.sin(), -phi.cos() * latitude.cos())
+}
+
+// Bilinear lookup in a lat-long map, wrapping around horizontally
+pub fn sample(map: &Framebuffer<Vec3f>, uv: Vec2f) -> Vec3f {
+    let x = uv.x * map.width as f32 - 0.5;
+    let y = (uv.y * map.height as f32 - 0.5).clamp(0.0, (map.height - 1) as f32);
+    let (x0, y0) = (x.floor(), y.floor());
+    let (tx, ty) = (x - x0, y - y0);
+
+    let wrap = |x: f32| (x as i64).rem_euclid(map.width as i64) as usize;
+    let (x0, x1) = (wrap(x0), wrap(x0 + 1.0));
+    let (y0, y1) = (y0 as usize, (y0 as usize + 1).min(map.height - 1));
+    let row = |y: usize| map.get(x0, y) * (1.0 - tx) + map.get(x1, y) * tx;
+    row(y0) * (1.0 - ty) + row(y1) * ty
+}
+
+// Trilinear lookup in a chain of maps
+fn sample_level(levels: &[Framebuffer<Vec3f>], uv: Vec2f, level: f32) -> Vec3f {
+    let level = level.clamp(0.0, (levels.len() - 1) as f32);
+    let (l0, t) = (level.floor() as usize, level.fract());
+    let c0 = sample(&levels[l0], uv);
+    if t == 0.0 {
+        c0
+    } else {
+        c0 * (1.0 - t) + sample(&levels[l0 + 1], uv) * t
+    }
+}
+
+// Linear map from a HDR image loaded by stb_image, whose rows are stored
+// from top to bottom
+pub fn from_hdr_image(image: &image::Image<f32>) -> Framebuffer<Vec3f> {
+    let mut map = Framebuffer::new(image.width, image.height, Vec3f::default());
+    for y in 0..image.height {
+        for x in 0..image.width {
+            let i = ((image.height - 1 - y) * image.width + x) * image.depth;
+            let c = if image.depth >= 3 {
+                Vec3f::new(image.data[i], image.data[i + 1], image.data[i + 2])
+            } else {
+                Vec3f::new(image.data[i], image.data[i], image.data[i])
+            };
+            map.set(x, y, c);
+        }
+    }
+    map
+}
+
+fn downsampled(map: &Framebuffer<Vec3f>, width: usize, height: usize) -> Framebuffer<Vec3f> {
+    let src: Vec<Vec4f> = map.data().iter().map(|&c| Vec4f::from_vec3f(c, 1.0)).collect();
+    let dst = resample::resample(&src, map.width, map.height, width, height, Filter::Box);
+    let mut res = Framebuffer::new(width, height, Vec3f::default());
+    for (i, c) in dst.into_iter().enumerate() {
+        res.set(i % width, i / width, Vec3f::from(c));
+    }
+    res
+}
+
+fn hammersley(i: usize, count: usize) -> Vec2f {
+    Vec2f::new(i as f32 / count as f32, (i as u32).reverse_bits() as f32 / 4_294_967_296.0)
+}
+
+// Half vector around `n` following the GGX distribution
+fn importance_sample_ggx(xi: Vec2f, n: Vec3f, alpha: f32) -> Vec3f {
+    let phi = 2.0 * PI * xi.x;
+    let cos_theta = ((1.0 - xi.y) / (1.0 + (alpha * alpha - 1.0) * xi.y)).sqrt();
+    let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+
+    let up = if n.z.abs() < 0.999 { Vec3f::new(0.0, 0.0, 1.0) } else { Vec3f::new(1.0, 0.0, 0.0) };
+    let tangent = up.cross(n).normalized();
+    let bitangent = n.cross(tangent);
+    tangent * (phi.cos() * sin_theta) + bitangent * (phi.sin() * sin_theta) + n * cos_theta
+}
+
+// Radiance of the environment convolved with the GGX lobe of `roughness`,
+// assuming the view direction is the normal. The samples are read from the
+// level of `mips` whose texels cover the solid angle of a sample, to avoid
+// the noise of the bright spots.
+fn prefilter(mips: &[Framebuffer<Vec3f>], width: usize, height: usize, roughness: f32) -> Framebuffer<Vec3f> {
+    let alpha = roughness * roughness;
+    let texel_solid_angle = 4.0 * PI / (mips[0].width * mips[0].height) as f32;
+
+    let mut res = Framebuffer::new(width, height, Vec3f::default());
+    for y in 0..height {
+        for x in 0..width {
+            let n = uv_to_direction(Vec2f::new((x as f32 + 0.5) / width as f32, (y as f32 + 0.5) / height as f32));
+            let mut total = Vec3f::default();
+            let mut weight = 0.0;
+            for i in 0..SPECULAR_SAMPLES {
+                let h = importance_sample_ggx(hammersley(i, SPECULAR_SAMPLES), n, alpha);
+                let n_dot_h = n.dot(h).max(0.0);
+                let l = h * (2.0 * n_dot_h) - n;
+                let n_dot_l = n.dot(l);
+                if n_dot_l > 0.0 {
+                    let pdf = pbr::distribution_ggx(n_dot_h, alpha) / 4.0;
+                    let sample_solid_angle = 1.0 / (SPECULAR_SAMPLES as f32 * pdf + 1e-4);
+                    let level = 0.5 * (sample_solid_angle / texel_solid_angle).log2() + 1.0;
+                    total += sample_level(mips, direction_to_uv(l), level) * n_dot_l;
+                    weight += n_dot_l;
+                }
+            }
+            res.set(x, y, total * (1.0 / weight));
+        }
+    }
+    res
+}
+
+// Scale and bias of f0 in the specular reflectance, averaged over the GGX
+// lobe for a white environment
+fn integrate_brdf(n_dot_v: f32, roughness: f32) -> Vec2f {
+    let n = Vec3f::new(0.0, 0.0, 1.0);
+    let v = Vec3f::new((1.0 - n_dot_v * n_dot_v).sqrt(), 0.0, n_dot_v);
+    let (mut scale, mut bias) = (0.0, 0.0);
+    for i in 0..BRDF_LUT_SAMPLES {
+        let h = importance_sample_ggx(hammersley(i, BRDF_LUT_SAMPLES), n, roughness * roughness);
+        let v_dot_h = v.dot(h).max(0.0);
+        let l = h * (2.0 * v_dot_h) - v;
+        if l.z > 0.0 {
+            let g = pbr::geometry_smith_ibl(n_dot_v, l.z, roughness);
+            let g_vis = g * v_dot_h / (h.z * n_dot_v);
+            let fc = (1.0 - v_dot_h).powi(5);
+            scale += (1.0 - fc) * g_vis;
+            bias += fc * g_vis;
+        }
+    }
+    Vec2f::new(scale, bias) * (1.0 / BRDF_LUT_SAMPLES as f32)
+}
+
+// Irradiance of an environment as 9 spherical harmonics coefficients, see
+// Ramamoorthi and Hanrahan, "An Efficient Representation for Irradiance
+// Environment Maps"
+#[derive(Debug, Clone, Copy)]
+pub struct ShIrradiance {
+    coefs: [Vec3f; 9],
+}
+
+fn sh_basis(d: Vec3f) -> [f32; 9] {
+    [
+        0.282_095,
+        0.488_603 * d.y,
+        0.488_603 * d.z,
+        0.488_603 * d.x,
+        1.092_548 * d.x * d.y,
+        1.092_548 * d.y * d.z,
+        0.315_392 * (3.0 * d.z * d.z - 1.0),
+        1.092_548 * d.x * d.z,
+        0.546_274 * (d.x * d.x - d.y * d.y),
+    ]
+}
+
+impl ShIrradiance {
+    pub fn project(map: &Framebuffer<Vec3f>) -> ShIrradiance {
+        let mut coefs = [Vec3f::default(); 9];
+        let texel_area = (2.0 * PI / map.width as f32) * (PI / map.height as f32);
+        for y in 0..map.height {
+            let v = (y as f32 + 0.5) / map.height as f32;
+            let solid_angle = texel_area * ((v - 0.5) * PI).cos();
+            for x in 0..map.width {
+                let d = uv_to_direction(Vec2f::new((x as f32 + 0.5) / map.width as f32, v));
+                let radiance = map.get(x, y) * solid_angle;
+                for (c, b) in coefs.iter_mut().zip(sh_basis(d).iter()) {
+                    *c += radiance * *b;
+                }
+            }
+        }
+
+        // Convolution with the clamped cosine, band by band
+        let bands = [PI, 2.0 * PI / 3.0, PI / 4.0];
+        for (i, c) in coefs.iter_mut().enumerate() {
+            let band = match i {
+                0 => 0,
+                1..=3 => 1,
+                _ => 2,
+            };
+            *c = *c * bands[band];
+        }
+        ShIrradiance { coefs }
+    }
+
+    // Irradiance over pi, the radiance of an ambient light lighting a white
+    // Lambertian surface of normal `n` the same way
+    pub fn ambient(&self, n: Vec3f) -> Vec3f {
+        let mut irradiance = Vec3f::default();
+        for (c, b) in self.coefs.iter().zip(sh_basis(n).iter()) {
+            irradiance += *c * *b;
+        }
+        Vec3f::new(irradiance.x.max(0.0), irradiance.y.max(0.0), irradiance.z.max(0.0)) * (1.0 / PI)
+    }
+}
+
+pub struct Environment {
+    // Scale of the radiance of the map
+    pub intensity: f32,
+    irradiance: ShIrradiance,
+    // Roughness from 0 on the first level to 1 on the last one
+    specular: Vec<Framebuffer<Vec3f>>,
+    // Indexed by the cosine of the view angle horizontally and the roughness
+    // vertically
+    brdf_lut: Framebuffer<Vec2f>,
+}
+
+impl Environment {
+    pub fn new(image: &image::Image<f32>) -> Environment {
+        let base = downsampled(&from_hdr_image(image), SPECULAR_WIDTH, SPECULAR_WIDTH / 2);
+
+        let mut mips = vec![base];
+        for level in 1..SPECULAR_LEVELS {
+            mips.push(downsampled(&mips[0], SPECULAR_WIDTH >> level, SPECULAR_WIDTH >> (level + 1)));
+        }
+
+        let mut specular = vec![mips[0].clone()];
+        for level in 1..SPECULAR_LEVELS {
+            let roughness = level as f32 / (SPECULAR_LEVELS - 1) as f32;
+            specular.push(prefilter(&mips, SPECULAR_WIDTH >> level, SPECULAR_WIDTH >> (level + 1), roughness));
+        }
+
+        let mut brdf_lut = Framebuffer::new(BRDF_LUT_SIZE, BRDF_LUT_SIZE, Vec2f::new(0.0, 0.0));
+        for y in 0..BRDF_LUT_SIZE {
+            for x in 0..BRDF_LUT_SIZE {
+                let (n_dot_v, roughness) = ((x as f32 + 0.5) / BRDF_LUT_SIZE as f32, (y as f32 + 0.5) / BRDF_LUT_SIZE as f32);
+                brdf_lut.set(x, y, integrate_brdf(n_dot_v, roughness));
+            }
+        }
+
+        Environment {
+            intensity: 1.0,
+            irradiance: ShIrradiance::project(&mips[0]),
+            specular,
+            brdf_lut,
+        }
+    }
+
+    // Ambient radiance lighting the diffuse of a surface of normal `n`
+    pub fn irradiance(&self, n: Vec3f) -> Vec3f {
+        self.irradiance.ambient(n) * self.intensity
+    }
+
+    // Radiance reflected in the direction `r` by a surface of `roughness`
+    pub fn specular(&self, r: Vec3f, roughness: f32) -> Vec3f {
+        let level = roughness.clamp(0.0, 1.0) * (SPECULAR_LEVELS - 1) as f32;
+        sample_level(&self.specular, direction_to_uv(r), level) * self.intensity
+    }
+
+    // Bilinear lookup in the BRDF table
+    pub fn brdf(&self, n_dot_v: f32, roughness: f32) -> Vec2f {
+        let size = BRDF_LUT_SIZE as f32;
+        let x = (n_dot_v * size - 0.5).clamp(0.0, size - 1.0);
+        let y = (roughness * size - 0.5).clamp(0.0, size - 1.0);
+        let (x0, y0) = (x.floor() as usize, y.floor() as usize);
+        let (x1, y1) = ((x0 + 1).min(BRDF_LUT_SIZE - 1), (y0 + 1).min(BRDF_LUT_SIZE - 1));
+        let (tx, ty) = (x.fract(), y.fract());
+        let row = |y: usize| self.brdf_lut.get(x0, y) * (1.0 - tx) + self.brdf_lut.get(x1, y) * tx;
+        row(y0) * (1.0 - ty) + row(y1) * ty
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lat_long_test() {
+        let forward = Vec3f::new(0.0, 0.0, -1.0);
+        assert!((direction_to_uv(forward) - Vec2f::new(0.5, 0.5)).length() < 1e-6);
+        assert!((direction_to_uv(Vec3f::new(0.0, 1.0, 0.0)).y - 1.0).abs() < 1e-6);
+
+        for &(u, v) in [(0.1, 0.2), (0.5, 0.9), (0.75, 0.5), (0.99, 0.01)].iter() {
+            let uv = direction_to_uv(uv_to_direction(Vec2f::new(u, v)));
+            assert!((uv - Vec2f::new(u, v)).length() < 1e-4, "{} {}", uv.x, uv.y);
+        }
+    }
+
+    #[test]
+    fn environment_test() {
+        // A uniform environment lights like an ambient light of its radiance
+        let image = image::Image::new(64, 32, 3, vec![0.5; 64 * 32 * 3]);
+        let environment = Environment::new(&image);
+        for &n in [Vec3f::new(0.0, 1.0, 0.0), Vec3f::new(0.6, 0.0, -0.8), Vec3f::new(0.0, -1.0, 0.0)].iter() {
+            assert!((environment.irradiance(n) - Vec3f::new(0.5, 0.5, 0.5)).length() < 0.01);
+            for &roughness in [0.0, 0.3, 1.0].iter() {
+                assert!((environment.specular(n, roughness) - Vec3f::new(0.5, 0.5, 0.5)).length() < 0.01);
+            }
+        }
+
+        // A white environment reflects everything off a mirror facing it,
+        // less at grazing angles and for rough surfaces
+        let mirror = environment.brdf(1.0, 0.0);
+        assert!((mirror.x + mirror.y - 1.0).abs() < 0.05, "{} {}", mirror.x, mirror.y);
+        let rough = environment.brdf(0.5, 1.0);
+        assert!(rough.x + rough.y < mirror.x + mirror.y);
+        assert!(rough.x >= 0.0 && rough.y >= 0.0);
+    }
+}
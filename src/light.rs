@@ -0,0 +1,269 @@
+use math::{Mat44, Vec3f};
+use shadow::ShadowFilter;
+
+// Lights of the scene and the light they cast on a point. Colors are linear,
+// scaled by the intensity.
+
+// Inverse square falloff, windowed so that it reaches 0 at the range.
+// The distance is offset by 1 so that it stays finite at the light.
+fn attenuation(distance: f32, range: f32) -> f32 {
+    let d = distance / range;
+    let window = (1.0 - d * d * d * d).clamp(0.0, 1.0);
+    window * window / (distance * distance + 1.0)
+}
+
+// Up vector of the views from a light, not parallel to its direction
+pub fn light_up(direction: Vec3f) -> Vec3f {
+    if direction.normalized().y.abs() > 0.99 {
+        Vec3f::new(1.0, 0.0, 0.0)
+    } else {
+        Vec3f::new(0.0, 1.0, 0.0)
+    }
+}
+
+// Light arriving at a point
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Incident {
+    // From a single direction, normalized and pointing towards the light
+    Direct { direction: Vec3f, radiance: Vec3f },
+    // From every direction, already integrated over the hemisphere
+    Ambient(Vec3f),
+}
+
+// Parallel light, as from the sun
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DirectionalLight {
+    // Normalized, pointing towards the light
+    pub direction: Vec3f,
+    pub color: Vec3f,
+    pub intensity: f32,
+    // Filter of the shadows, None for the filter of the shadow settings
+    pub shadow_filter: Option<ShadowFilter>,
+}
+
+impl DirectionalLight {
+    pub fn new(direction: Vec3f) -> DirectionalLight {
+        DirectionalLight {
+            direction: direction.normalized(),
+            color: Vec3f::new(1.0, 1.0, 1.0),
+            intensity: 1.0,
+            shadow_filter: None,
+        }
+    }
+}
+
+// Light radiating in every direction from a point
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PointLight {
+    pub position: Vec3f,
+    // Linear color
+    pub color: Vec3f,
+    pub intensity: f32,
+    // Distance at which the light fades out completely
+    pub range: f32,
+    pub shadow_filter: Option<ShadowFilter>,
+}
+
+impl PointLight {
+    pub fn new(position: Vec3f) -> PointLight {
+        PointLight {
+            position,
+            color: Vec3f::new(1.0, 1.0, 1.0),
+            intensity: 1.0,
+            range: 5.0,
+            shadow_filter: None,
+        }
+    }
+
+    pub fn attenuation(&self, distance: f32) -> f32 {
+        attenuation(distance, self.range)
+    }
+
+    // Direction towards the light and light color reaching a world space point
+    pub fn illuminate(&self, world_pos: Vec3f) -> (Vec3f, Vec3f) {
+        let to_light = self.position - world_pos;
+        let distance = to_light.length();
+        let radiance = self.color * (self.intensity * self.attenuation(distance));
+        (to_light * (1.0 / distance.max(1e-6)), radiance)
+    }
+}
+
+// Light shining from a point in a cone
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpotLight {
+    pub position: Vec3f,
+    // Axis of the cone, normalized
+    pub direction: Vec3f,
+    // Linear color
+    pub color: Vec3f,
+    pub intensity: f32,
+    // Distance at which the light fades out completely
+    pub range: f32,
+    // Half angles of the cone in radians, the light is at full intensity
+    // inside the inner angle and fades out smoothly up to the outer angle
+    pub inner_angle: f32,
+    pub outer_angle: f32,
+    // Texture projected from the light, tinting it like a gobo
+    pub cookie: Option<String>,
+    pub shadow_filter: Option<ShadowFilter>,
+}
+
+impl SpotLight {
+    pub fn new(position: Vec3f, direction: Vec3f) -> SpotLight {
+        SpotLight {
+            position,
+            direction: direction.normalized(),
+            color: Vec3f::new(1.0, 1.0, 1.0),
+            intensity: 1.0,
+            range: 10.0,
+            inner_angle: 20_f32.to_radians(),
+            outer_angle: 30_f32.to_radians(),
+            cookie: None,
+            shadow_filter: None,
+        }
+    }
+
+    // Smoothstep between the cosines of the outer and inner angles
+    pub fn cone_falloff(&self, dir_from_light: Vec3f) -> f32 {
+        let (cos_outer, cos_inner) = (self.outer_angle.cos(), self.inner_angle.cos());
+        let cos = dir_from_light.dot(self.direction);
+        let t = ((cos - cos_outer) / (cos_inner - cos_outer).max(1e-6)).clamp(0.0, 1.0);
+        t * t * (3.0 - 2.0 * t)
+    }
+
+    // Perspective projection of the cone from world space to clip space,
+    // with the depth from `near` to the range
+    pub fn clip_from_world(&self, near: f32) -> Mat44 {
+        let view_from_world = Mat44::lookat(self.position - self.direction, self.position, light_up(self.direction));
+        Mat44::perspective(self.outer_angle * 2.0, 1.0, near, self.range) * view_from_world
+    }
+
+    // Direction towards the light and light color reaching a world space
+    // point, without the cookie
+    pub fn illuminate(&self, world_pos: Vec3f) -> (Vec3f, Vec3f) {
+        let to_light = self.position - world_pos;
+        let distance = to_light.length();
+        let dir = to_light * (1.0 / distance.max(1e-6));
+        let falloff = attenuation(distance, self.range) * self.cone_falloff(-dir);
+        (dir, self.color * (self.intensity * falloff))
+    }
+
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Light {
+    Directional(DirectionalLight),
+    Point(PointLight),
+    Spot(SpotLight),
+    // Same light from every direction
+    Ambient { color: Vec3f, intensity: f32 },
+    // Ambient light blending from the ground color for the normals facing
+    // down to the sky color for those facing up
+    Hemisphere { color: Vec3f, ground_color: Vec3f, intensity: f32 },
+}
+
+impl Light {
+    pub fn color_mut(&mut self) -> &mut Vec3f {
+        match self {
+            Light::Directional(light) => &mut light.color,
+            Light::Point(light) => &mut light.color,
+            Light::Spot(light) => &mut light.color,
+            Light::Ambient { color, .. } | Light::Hemisphere { color, .. } => color,
+        }
+    }
+
+    pub fn intensity_mut(&mut self) -> &mut f32 {
+        match self {
+            Light::Directional(light) => &mut light.intensity,
+            Light::Point(light) => &mut light.intensity,
+            Light::Spot(light) => &mut light.intensity,
+            Light::Ambient { intensity, .. } | Light::Hemisphere { intensity, .. } => intensity,
+        }
+    }
+
+    // Light arriving at a world space point of normal `normal`, without shadows
+    pub fn incident(&self, world_pos: Vec3f, normal: Vec3f) -> Incident {
+        match self {
+            Light::Directional(light) => Incident::Direct {
+                direction: light.direction,
+                radiance: light.color * light.intensity,
+            },
+            Light::Point(light) => {
+                let (direction, radiance) = light.illuminate(world_pos);
+                Incident::Direct { direction, radiance }
+            }
+            Light::Spot(light) => {
+                let (direction, radiance) = light.illuminate(world_pos);
+                Incident::Direct { direction, radiance }
+            }
+            Light::Ambient { color, intensity } => Incident::Ambient(*color * *intensity),
+            Light::Hemisphere { color, ground_color, intensity } => {
+                let t = 0.5 + 0.5 * normal.y;
+                Incident::Ambient((*ground_color * (1.0 - t) + *color * t) * *intensity)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn attenuation_test() {
+        let light = PointLight::new(Vec3f::new(0.0, 0.0, 0.0));
+        assert_eq!(light.attenuation(0.0), 1.0);
+        assert_eq!(light.attenuation(light.range), 0.0);
+        assert_eq!(light.attenuation(light.range * 2.0), 0.0);
+
+        let mut previous = light.attenuation(0.0);
+        for i in 1..50 {
+            let attenuation = light.attenuation(i as f32 * 0.1);
+            assert!(attenuation < previous);
+            previous = attenuation;
+        }
+
+        let (dir, radiance) = light.illuminate(Vec3f::new(0.0, 2.0, 0.0));
+        assert!((dir.y + 1.0).abs() < 1e-6);
+        assert!((radiance.x - light.attenuation(2.0)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn cone_falloff_test() {
+        let mut light = SpotLight::new(Vec3f::new(0.0, 0.0, 0.0), Vec3f::new(0.0, -2.0, 0.0));
+        light.inner_angle = 10_f32.to_radians();
+        light.outer_angle = 20_f32.to_radians();
+        let at_angle = |degrees: f32| {
+            let a = degrees.to_radians();
+            light.cone_falloff(Vec3f::new(a.sin(), -a.cos(), 0.0))
+        };
+        assert_eq!(at_angle(0.0), 1.0);
+        assert_eq!(at_angle(9.0), 1.0);
+        assert!(at_angle(15.0) > 0.0 && at_angle(15.0) < 1.0);
+        assert!(at_angle(12.0) > at_angle(18.0));
+        assert_eq!(at_angle(21.0), 0.0);
+        assert_eq!(at_angle(180.0), 0.0);
+
+        let (_, radiance) = light.illuminate(Vec3f::new(0.0, 1.0, 0.0));
+        assert_eq!(radiance, Vec3f::new(0.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn incident_test() {
+        let (pos, up) = (Vec3f::new(0.0, 0.0, 0.0), Vec3f::new(0.0, 1.0, 0.0));
+
+        let mut sun = Light::Directional(DirectionalLight::new(Vec3f::new(0.0, 2.0, 0.0)));
+        *sun.intensity_mut() = 2.0;
+        let expected = Incident::Direct { direction: up, radiance: Vec3f::new(2.0, 2.0, 2.0) };
+        assert_eq!(sun.incident(pos, up), expected);
+
+        let sky = Light::Hemisphere {
+            color: Vec3f::new(0.0, 0.0, 1.0),
+            ground_color: Vec3f::new(1.0, 0.0, 0.0),
+            intensity: 1.0,
+        };
+        assert_eq!(sky.incident(pos, up), Incident::Ambient(Vec3f::new(0.0, 0.0, 1.0)));
+        assert_eq!(sky.incident(pos, -up), Incident::Ambient(Vec3f::new(1.0, 0.0, 0.0)));
+        assert_eq!(sky.incident(pos, Vec3f::new(1.0, 0.0, 0.0)), Incident::Ambient(Vec3f::new(0.5, 0.0, 0.5)));
+    }
+}
@@ -1,205 +1,172 @@
 extern crate stb_image;
+mod color;
+mod config;
+mod draw;
+mod framebuffer;
+mod gbuffer;
+mod hdr;
+mod ibl;
+mod light;
 mod math;
 mod obj;
+mod oit;
+mod pbr;
 mod ppm;
-
+mod raster;
+mod resample;
+mod shadow;
+mod sky;
+mod ssao;
+mod text;
+mod tonemap;
+
+use config::{RenderConfig, RenderMode, ShadingModel};
+use framebuffer::Framebuffer;
+use gbuffer::{GBuffer, GSample};
+use color::BlendMode;
 use math::{Mat33, Mat44, Vec2f, Vec3f, Vec4f};
+use raster::RasterizerState;
+use ibl::Environment;
+use light::{Incident, Light};
+use pbr::{MaterialSettings, Surface};
+use shadow::{CascadedShadowMap, CubeShadowMap, LightShadow, ShadowFilter, ShadowMap, ShadowSettings, ViewFrustum};
 use stb_image::image;
+use sky::{Sky, SkySettings};
+use ssao::AmbientOcclusion;
+use std::collections::HashSet;
 use std::fs::{DirBuilder, File};
-use std::io::{Read, Write};
+use std::io::{BufWriter, Read, Write};
+use std::time::Instant;
 
 use image::LoadResult::{Error, ImageF32, ImageU8};
 
 const WIDTH: usize = 800;
 const HEIGHT: usize = 800;
-const FWIDTH: f32 = WIDTH as f32;
-const FHEIGHT: f32 = HEIGHT as f32;
 
 const MAX_DEPTH: f32 = 2000.0;
 
-#[allow(dead_code)]
-fn line(x0: i32, y0: i32, x1: i32, y1: i32, image: &mut ppm::Image, color: ppm::RGB) {
-    let steep = (x0 - x1).abs() < (y0 - y1).abs();
-    let (x0, x1, y0, y1) = if steep {
-        (y0, y1, x0, x1)
-    } else {
-        (x0, x1, y0, y1)
-    }; // SWAP
-    let (x0, x1, y0, y1) = if x0 > x1 {
-        (x1, x0, y1, y0)
-    } else {
-        (x0, x1, y0, y1)
-    }; // SWAP
-
-    let (dx, dy) = (x1 - x0, y1 - y0);
-    let (derror, mut error) = (dy.abs() * 2, 0);
-
-    let mut y = y0;
-    let yinc = if y1 > y0 { 1 } else { -1 };
-
-    for x in x0..x1 {
-        if steep {
-            image.set(y as usize, x as usize, color);
-        } else {
-            image.set(x as usize, y as usize, color);
-        }
-
-        error += derror;
-        if error > dx {
-            y += yinc;
-            error -= dx * 2;
-        }
-    }
-}
-
-fn barycenter(a: Vec2f, b: Vec2f, c: Vec2f, p: Vec2f) -> Vec3f {
-    let v1 = Vec3f {
-        x: c.x - a.x,
-        y: b.x - a.x,
-        z: a.x - p.x,
-    };
+const MODEL_NAME: &str = "african_head.obj";
 
-    let v2 = Vec3f {
-        x: c.y - a.y,
-        y: b.y - a.y,
-        z: a.y - p.y,
-    };
+// Lines lie exactly on the surface of the triangles they outline, push them
+// towards the camera so that they are not hidden by their own triangles.
+const LINE_DEPTH_BIAS: f32 = 4.0;
 
-    let u = v1.cross(v2);
-    if u.z.abs() < 1_f32 {
-        Vec3f::new(-1_f32, 1_f32, 1_f32)
-    } else {
-        Vec3f::new(1_f32 - (u.x + u.y) / u.z, u.y / u.z, u.x / u.z)
-    }
-}
+// Near plane of the point and spot light shadow maps, in world units. It only
+// sets the depth scale, nothing is clipped past it.
+const LIGHT_SHADOW_NEAR: f32 = 0.05;
 
-fn texture(image: &image::Image<u8>, uv: Vec2f) -> (u8, u8, u8) {
+// Index of the first channel of the texel under uv
+fn texel_index(image: &image::Image<u8>, uv: Vec2f) -> usize {
     let fnwidth = image.width as f32;
     let fnheight = image.height as f32;
     let nu = (uv.x * fnwidth) as usize;
     let nv = ((1.0 - uv.y) * fnheight) as usize; //flipped vertically
 
-    let pixel_index = (nv * image.width + nu) * image.depth;
+    (nv * image.width + nu) * image.depth
+}
+
+fn texture(image: &image::Image<u8>, uv: Vec2f) -> (u8, u8, u8) {
+    let pixel_index = texel_index(image, uv);
 
     (
-        image.data[pixel_index + 0],
+        image.data[pixel_index],
         image.data[pixel_index + 1],
         image.data[pixel_index + 2],
     )
 }
 
-trait Shader {
-    fn vertex(&mut self, face_index: usize) -> (Vec4f, Vec4f, Vec4f);
-    fn fragment(&self, bar: Vec3f) -> (u8, u8, u8);
+// Straight alpha of the texture, opaque when the image has no alpha channel
+fn texture_alpha(image: &image::Image<u8>, uv: Vec2f) -> f32 {
+    if image.depth == 4 {
+        f32::from(image.data[texel_index(image, uv) + 3]) / 255.0
+    } else {
+        1.0
+    }
 }
 
-struct PhongShader<'a> {
-    // Input to graphic pipeline
-    light_dir: Vec3f,
-    texture_map: &'a image::Image<u8>,
-    spec_map: &'a image::Image<u8>,
-    tangent_map: &'a image::Image<u8>,
-    mesh: &'a obj::Mesh,
-
-    trans_matrix: Mat44,
-
-
-    // Output from Vertex for frag
-    normals: [Vec3f; 3],
-    tangents: [Vec3f; 3],
-    uvs: [Vec2f; 3],
+// Color textures are authored in sRGB, decode them to linear for shading.
+// Data textures (normal, specular) must go through `texture` instead.
+fn texture_srgba(image: &image::Image<u8>, uv: Vec2f) -> Vec4f {
+    let (r, g, b) = texture(image, uv);
+    Vec4f::from_vec3f(color::decode_srgb8(r, g, b), texture_alpha(image, uv))
 }
 
-impl<'a> PhongShader<'a> {
-    fn new(light_dir: Vec3f, trans_matrix: Mat44, mesh: &'a obj::Mesh, texture_map: &'a image::Image<u8>, spec_map: &'a image::Image<u8>, tangent_map: &'a image::Image<u8>) -> PhongShader<'a> {
-        PhongShader { 
-            light_dir,
-            trans_matrix,
-            mesh, 
-            texture_map, 
-            spec_map,   
-            tangent_map,
-
-            uvs: [Vec2f::new(0.0, 0.0); 3], 
-            normals: [Vec3f::new(0.0, 0.0, 0.0); 3],
-            tangents: [Vec3f::new(0.0, 0.0, 0.0); 3],
+// Cutout materials discard the fragments whose texture alpha is below the cutoff
+#[derive(Clone, Copy)]
+struct AlphaTest<'a> {
+    alpha_map: &'a image::Image<u8>,
+    cutoff: f32,
+}
 
-        }
+impl<'a> AlphaTest<'a> {
+    fn discard(&self, uv: Vec2f) -> bool {
+        texture_alpha(self.alpha_map, uv) < self.cutoff
     }
 }
 
-impl<'a> Shader for PhongShader<'a> {
-
-    fn vertex(&mut self, face_index: usize) -> (Vec4f, Vec4f, Vec4f) {
-        let face = &self.mesh.faces[face_index];
-        let (v1, t1, n1) = face[0];
-        let (v2, t2, n2) = face[1];
-        let (v3, t3, n3) = face[2];
-
-        self.normals    = [self.mesh.normals[n1], self.mesh.normals[n2], self.mesh.normals[n3]];
-        self.uvs        = [self.mesh.texcoord[t1], self.mesh.texcoord[t2], self.mesh.texcoord[t3]];
-        self.tangents   = [self.mesh.tangents[v1], self.mesh.tangents[v2], self.mesh.tangents[v3]];
-
-        let v1_transformed = self.trans_matrix * Vec4f::from_vec3f(self.mesh.vertices[v1], 1.0);
-        let v2_transformed = self.trans_matrix * Vec4f::from_vec3f(self.mesh.vertices[v2], 1.0);
-        let v3_transformed = self.trans_matrix * Vec4f::from_vec3f(self.mesh.vertices[v3], 1.0);
+trait Shader {
+    fn vertex(&mut self, face_index: usize) -> (Vec4f, Vec4f, Vec4f);
+    // Returns the linear color of the fragment, with straight alpha,
+    // or None to discard the fragment
+    fn fragment(&self, bar: Vec3f) -> Option<Vec4f>;
 
-        (
-            v1_transformed,
-            v2_transformed,
-            v3_transformed,
-        )
+    // Two sided materials are never culled
+    fn two_sided(&self) -> bool {
+        false
     }
+}
 
-    fn fragment(&self, bar: Vec3f) -> (u8, u8, u8) {
-
-        let uv = self.uvs[0] * bar.x + self.uvs[1] * bar.y + self.uvs[2] * bar.z;
-
-        let bn = (self.normals[0] * bar.x + self.normals[1] * bar.y + self.normals[2] * bar.z).normalized();
-        let tangent = (self.tangents[0] * bar.x + self.tangents[1] * bar.y + self.tangents[2] * bar.z).normalized();
-        let bitangent = bn.cross(tangent).normalized();
-            
-        let tbn = Mat33::from_col_vec(tangent, bitangent, bn);
-        let tbn_inv = tbn.transposed();
-
-        let (nx, ny, nz) = texture(self.tangent_map, uv);
-        let get_normal_value = |c| {f32::from(c) / 255.0 * 2.0 - 1.0};
-
-        let bn = Vec3f::new(get_normal_value(nx), get_normal_value(ny), get_normal_value(nz));
-        let light_dir_tangentspace = tbn_inv * self.light_dir;
-
-        let diffuse = bn.dot(light_dir_tangentspace).max(0.0);
-
-        let reflected_dir = (bn * (bn.dot(light_dir_tangentspace) * 2.0) - light_dir_tangentspace).normalized();
-        let (spec, _, _) = texture(self.spec_map, uv);
-        let spec = reflected_dir.z.max(0.0).powf(f32::from(spec));
-
-        let (r, g, b) = texture(self.texture_map, uv);
-        let (r, g, b) = (f32::from(r), f32::from(g), f32::from(b));
+// Shaders of the deferred path split their fragment stage in two: the surface
+// seen by the fragment, or None to discard it, then its lighting, as a linear
+// color with straight alpha. Their forward fragment lights their surface.
+trait DeferredShader: Shader {
+    fn surface(&self, bar: Vec3f) -> Option<GSample>;
 
-        let light_calc = |c: f32| -> u8 {
-            (c * (diffuse + 0.6 * spec)) as u8
-        };
+    fn lighting(&self, sample: &GSample) -> Vec4f;
+}
 
-        (light_calc(r), light_calc(g), light_calc(b))
+// Phong reflection of a light on a normal mapped surface, as a factor of the
+// albedo. The highlights are measured around the normal of the mesh, before
+// normal mapping. Ambient light is not reflected specularly.
+fn phong_reflection(incident: Incident, normal: Vec3f, vertex_normal: Vec3f, shininess: f32, specular_strength: f32) -> Vec3f {
+    match incident {
+        Incident::Direct { direction, radiance } => {
+            let diffuse = normal.dot(direction).max(0.0);
+            let reflected_dir = (normal * (normal.dot(direction) * 2.0) - direction).normalized();
+            let specular = reflected_dir.dot(vertex_normal).max(0.0).powf(shininess);
+            radiance * (diffuse + specular_strength * specular)
+        }
+        Incident::Ambient(radiance) => radiance,
     }
 }
 
+// Accessibility of the ambient light at a point of the mesh, from the screen
+// space ambient occlusion at its pixel
+fn ambient_occlusion(ao: Option<&AmbientOcclusion>, screen_from_world: Mat44, world_pos: Vec3f) -> f32 {
+    ao.map_or(1.0, |ao| ao.at((screen_from_world * Vec4f::from_vec3f(world_pos, 1.0)).homogenize().xy()))
+}
+
 struct DepthShader<'a> {
     trans_matrix: Mat44,
+    alpha_test: Option<AlphaTest<'a>>,
+    two_sided: bool,
     mesh: &'a obj::Mesh,
 
 
     // Vectex Output, Frag intput
     vertices: [Vec3f; 3],
+    uvs: [Vec2f; 3],
 }
 
 impl<'a> DepthShader<'a> {
     fn new(trans_matrix: Mat44, mesh: &'a obj::Mesh) -> DepthShader<'a> {
         DepthShader {
             trans_matrix,
+            alpha_test: None,
+            two_sided: false,
             mesh,
-            vertices: [Vec3f::new(0.0, 0.0, 0.0); 3]
+            vertices: [Vec3f::new(0.0, 0.0, 0.0); 3],
+            uvs: [Vec2f::new(0.0, 0.0); 3],
         }
     }
 }
@@ -208,9 +175,11 @@ impl<'a> Shader for DepthShader<'a> {
     fn vertex(&mut self, face_index: usize) -> (Vec4f, Vec4f, Vec4f) {
 
         let face = &self.mesh.faces[face_index];
-        let (v1, _, _) = face[0];
-        let (v2, _, _) = face[1];
-        let (v3, _, _) = face[2];
+        let (v1, t1, _) = face[0];
+        let (v2, t2, _) = face[1];
+        let (v3, t3, _) = face[2];
+
+        self.uvs = [self.mesh.texcoord[t1], self.mesh.texcoord[t2], self.mesh.texcoord[t3]];
 
 
         let v1_transformed = self.trans_matrix * Vec4f::from_vec3f(self.mesh.vertices[v1], 1.0);
@@ -225,54 +194,158 @@ impl<'a> Shader for DepthShader<'a> {
         )
     }
 
-    fn fragment(&self, bar: Vec3f) -> (u8, u8, u8) {
+    fn fragment(&self, bar: Vec3f) -> Option<Vec4f> {
+
+        if let Some(alpha_test) = self.alpha_test {
+            let uv = self.uvs[0] * bar.x + self.uvs[1] * bar.y + self.uvs[2] * bar.z;
+            if alpha_test.discard(uv) {
+                return None;
+            }
+        }
 
         let v = self.vertices[0] * bar.x + self.vertices[1] * bar.y + self.vertices[2] * bar.z;
 
         let s = v.z / MAX_DEPTH;
-        let c = (255.0 * s) as u8;
-        (c, c, c) 
+        Some(Vec4f::new(s, s, s, 1.0))
+    }
+
+    fn two_sided(&self) -> bool {
+        self.two_sided
+    }
+}
+
+// Output from Vertex for frag of the lit shaders: the attributes of the
+// vertices of a face, interpolated with the barycentric coordinates
+#[derive(Clone, Copy, Default)]
+struct SurfaceVaryings {
+    normals: [Vec3f; 3],
+    tangents: [Vec3f; 3],
+    uvs: [Vec2f; 3],
+    world_positions: [Vec3f; 3],
+}
+
+impl SurfaceVaryings {
+    fn new(mesh: &obj::Mesh, face_index: usize) -> SurfaceVaryings {
+        let face = &mesh.faces[face_index];
+        let (v1, t1, n1) = face[0];
+        let (v2, t2, n2) = face[1];
+        let (v3, t3, n3) = face[2];
+
+        SurfaceVaryings {
+            normals: [mesh.normals[n1], mesh.normals[n2], mesh.normals[n3]],
+            tangents: [mesh.tangents[v1], mesh.tangents[v2], mesh.tangents[v3]],
+            uvs: [mesh.texcoord[t1], mesh.texcoord[t2], mesh.texcoord[t3]],
+            world_positions: [mesh.vertices[v1], mesh.vertices[v2], mesh.vertices[v3]],
+        }
+    }
+
+    // Vertices of the face transformed by the matrix
+    fn transformed(&self, trans_matrix: Mat44) -> (Vec4f, Vec4f, Vec4f) {
+        let p = self.world_positions;
+        (
+            trans_matrix * Vec4f::from_vec3f(p[0], 1.0),
+            trans_matrix * Vec4f::from_vec3f(p[1], 1.0),
+            trans_matrix * Vec4f::from_vec3f(p[2], 1.0),
+        )
+    }
+
+    fn uv(&self, bar: Vec3f) -> Vec2f {
+        self.uvs[0] * bar.x + self.uvs[1] * bar.y + self.uvs[2] * bar.z
+    }
+
+    fn world_pos(&self, bar: Vec3f) -> Vec3f {
+        self.world_positions[0] * bar.x + self.world_positions[1] * bar.y + self.world_positions[2] * bar.z
+    }
+
+    // World space normal of the mesh, and the normal from the tangent space
+    // normal map
+    fn normals(&self, bar: Vec3f, uv: Vec2f, tangent_map: &image::Image<u8>) -> (Vec3f, Vec3f) {
+        let bn = (self.normals[0] * bar.x + self.normals[1] * bar.y + self.normals[2] * bar.z).normalized();
+        let tangent = (self.tangents[0] * bar.x + self.tangents[1] * bar.y + self.tangents[2] * bar.z).normalized();
+        let bitangent = bn.cross(tangent).normalized();
+        let tbn = Mat33::from_col_vec(tangent, bitangent, bn);
+
+        let (nx, ny, nz) = texture(tangent_map, uv);
+        let get_normal_value = |c| {f32::from(c) / 255.0 * 2.0 - 1.0};
+
+        (bn, (tbn * Vec3f::new(get_normal_value(nx), get_normal_value(ny), get_normal_value(nz))).normalized())
     }
 }
 
+// Texture projected from a spot light, tinting it like a gobo
+struct Cookie {
+    image: image::Image<u8>,
+    clip_from_world: Mat44,
+}
+
+impl Cookie {
+    fn color(&self, world_pos: Vec3f) -> Vec3f {
+        let ndc = (self.clip_from_world * Vec4f::from_vec3f(world_pos, 1.0)).homogenize();
+        let uv = |c: f32| ((c + 1.0) / 2.0).clamp(1e-4, 1.0 - 1e-4);
+        texture_srgba(&self.image, Vec2f::new(uv(ndc.x), uv(ndc.y))).xyz()
+    }
+}
+
+// Light of the scene, with its shadow map and its cookie
+struct SceneLight {
+    light: Light,
+    shadow: LightShadow,
+    cookie: Option<Cookie>,
+}
+
+impl SceneLight {
+    // Light arriving at a world space point, shadowed and tinted by the cookie
+    fn incident(&self, world_pos: Vec3f, normal: Vec3f) -> Incident {
+        match self.light.incident(world_pos, normal) {
+            Incident::Direct { direction, radiance } if radiance != Vec3f::default() => {
+                let mut radiance = radiance * self.shadow.visibility(world_pos);
+                if let Some(ref cookie) = self.cookie {
+                    radiance = radiance * cookie.color(world_pos);
+                }
+                Incident::Direct { direction, radiance }
+            }
+            incident => incident,
+        }
+    }
+}
+
+// Phong shading with shadows
 struct PhongDShader<'a> {
     // Input to graphic pipeline
-    light_dir: Vec3f,
+    lights: &'a [SceneLight],
+    // Scale of the specular highlights
+    specular_strength: f32,
     texture_map: &'a image::Image<u8>,
     spec_map: &'a image::Image<u8>,
     tangent_map: &'a image::Image<u8>,
-    depth_map: &'a [f32],
+    // Only lights the diffuse, as an ambient light
+    environment: Option<&'a Environment>,
+    ambient_occlusion: Option<&'a AmbientOcclusion>,
+    alpha_test: Option<AlphaTest<'a>>,
+    two_sided: bool,
     mesh: &'a obj::Mesh,
 
     trans_matrix: Mat44,
-    trans_matrix_inv: Mat44,
-    light_trans: Mat44,
-
 
-    // Output from Vertex for frag
-    normals: [Vec3f; 3],
-    tangents: [Vec3f; 3],
-    uvs: [Vec2f; 3],
-    vertices: [Vec4f; 3],
+    varyings: SurfaceVaryings,
 }
 
 impl<'a> PhongDShader<'a> {
-    fn new(light_dir: Vec3f, trans_matrix: Mat44, trans_matrix_inv: Mat44, light_trans: Mat44, mesh: &'a obj::Mesh, texture_map: &'a image::Image<u8>, spec_map: &'a image::Image<u8>, tangent_map: &'a image::Image<u8>, depth_map: &'a [f32]) -> PhongDShader<'a> {
+    fn new(lights: &'a [SceneLight], trans_matrix: Mat44, mesh: &'a obj::Mesh, texture_map: &'a image::Image<u8>, spec_map: &'a image::Image<u8>, tangent_map: &'a image::Image<u8>) -> PhongDShader<'a> {
         PhongDShader { 
-            light_dir,
+            lights,
+            specular_strength: 0.6,
             trans_matrix,
-            trans_matrix_inv,
-            light_trans,
             mesh, 
             texture_map, 
             spec_map,   
             tangent_map,
-            depth_map,
+            environment: None,
+            ambient_occlusion: None,
+            alpha_test: None,
+            two_sided: false,
 
-            uvs: [Vec2f::new(0.0, 0.0); 3], 
-            normals: [Vec3f::new(0.0, 0.0, 0.0); 3],
-            tangents: [Vec3f::new(0.0, 0.0, 0.0); 3],
-            vertices: [Vec4f::new(0.0, 0.0, 0.0, 0.0); 3],
+            varyings: SurfaceVaryings::default(),
         }
     }
 }
@@ -280,118 +353,440 @@ impl<'a> PhongDShader<'a> {
 impl<'a> Shader for PhongDShader<'a> {
 
     fn vertex(&mut self, face_index: usize) -> (Vec4f, Vec4f, Vec4f) {
-        let face = &self.mesh.faces[face_index];
-        let (v1, t1, n1) = face[0];
-        let (v2, t2, n2) = face[1];
-        let (v3, t3, n3) = face[2];
+        self.varyings = SurfaceVaryings::new(self.mesh, face_index);
+        self.varyings.transformed(self.trans_matrix)
+    }
 
-        let v1_transformed = self.trans_matrix * Vec4f::from_vec3f(self.mesh.vertices[v1], 1.0);
-        let v2_transformed = self.trans_matrix * Vec4f::from_vec3f(self.mesh.vertices[v2], 1.0);
-        let v3_transformed = self.trans_matrix * Vec4f::from_vec3f(self.mesh.vertices[v3], 1.0);
+    fn fragment(&self, bar: Vec3f) -> Option<Vec4f> {
+        self.surface(bar).map(|sample| self.lighting(&sample))
+    }
 
-        self.normals    = [self.mesh.normals[n1], self.mesh.normals[n2], self.mesh.normals[n3]];
-        self.uvs        = [self.mesh.texcoord[t1], self.mesh.texcoord[t2], self.mesh.texcoord[t3]];
-        self.tangents   = [self.mesh.tangents[v1], self.mesh.tangents[v2], self.mesh.tangents[v3]];
-        self.vertices   = [v1_transformed, v2_transformed, v3_transformed];                        
+    fn two_sided(&self) -> bool {
+        self.two_sided
+    }
+}
 
-        (
-            v1_transformed,
-            v2_transformed,
-            v3_transformed,
-        )
+impl<'a> DeferredShader for PhongDShader<'a> {
+    fn surface(&self, bar: Vec3f) -> Option<GSample> {
+
+        let uv = self.varyings.uv(bar);
+        if self.alpha_test.is_some_and(|t| t.discard(uv)) {
+            return None;
+        }
+
+        let world_pos = self.varyings.world_pos(bar);
+        let (bn, normal_worldspace) = self.varyings.normals(bar, uv, self.tangent_map);
+        let (spec, _, _) = texture(self.spec_map, uv);
+
+        Some(GSample {
+            position: world_pos,
+            normal: normal_worldspace,
+            vertex_normal: bn,
+            albedo: texture_srgba(self.texture_map, uv),
+            shininess: f32::from(spec),
+            occlusion: ambient_occlusion(self.ambient_occlusion, self.trans_matrix, world_pos),
+            ..GSample::default()
+        })
     }
 
-    fn fragment(&self, bar: Vec3f) -> (u8, u8, u8) {
+    fn lighting(&self, sample: &GSample) -> Vec4f {
+        let mut lighting = Vec3f::default();
+        for light in self.lights.iter() {
+            let incident = match light.incident(sample.position, sample.normal) {
+                Incident::Ambient(radiance) => Incident::Ambient(radiance * sample.occlusion),
+                incident => incident,
+            };
+            lighting += phong_reflection(incident, sample.normal, sample.vertex_normal, sample.shininess, self.specular_strength);
+        }
+        if let Some(environment) = self.environment {
+            lighting += environment.irradiance(sample.normal) * sample.occlusion;
+        }
 
-        let pos = self.vertices[0] * bar.x + self.vertices[1] * bar.y + self.vertices[2] * bar.z;
-        let pos_lightport = (self.light_trans * self.trans_matrix_inv * pos).homogenize();
+        Vec4f::from_vec3f(sample.albedo.xyz() * lighting, sample.albedo.w)
+    }
+}
 
-        let depth_map_x = pos_lightport.x as usize;
-        let depth_map_y = (pos_lightport.y * FWIDTH) as usize;
-        let depth_map_z = self.depth_map[depth_map_y + depth_map_x];
+// Metallic-roughness material, lit with the Cook-Torrance model of `pbr`
+struct PbrShader<'a> {
+    // Input to graphic pipeline
+    lights: &'a [SceneLight],
+    eye: Vec3f,
+    material: &'a MaterialSettings,
+    texture_map: &'a image::Image<u8>,
+    tangent_map: &'a image::Image<u8>,
+    metallic_map: Option<&'a image::Image<u8>>,
+    roughness_map: Option<&'a image::Image<u8>>,
+    occlusion_map: Option<&'a image::Image<u8>>,
+    emissive_map: Option<&'a image::Image<u8>>,
+    environment: Option<&'a Environment>,
+    ambient_occlusion: Option<&'a AmbientOcclusion>,
+    alpha_test: Option<AlphaTest<'a>>,
+    two_sided: bool,
+    mesh: &'a obj::Mesh,
 
-        let shadow = if depth_map_z < pos_lightport.z + 40.0 {
-            1.0
-        } else {
-            0.3
-        };
+    trans_matrix: Mat44,
 
-        let uv = self.uvs[0] * bar.x + self.uvs[1] * bar.y + self.uvs[2] * bar.z;
+    varyings: SurfaceVaryings,
+}
 
-        let bn = (self.normals[0] * bar.x + self.normals[1] * bar.y + self.normals[2] * bar.z).normalized();
-        let tangent = (self.tangents[0] * bar.x + self.tangents[1] * bar.y + self.tangents[2] * bar.z).normalized();
-        let bitangent = bn.cross(tangent).normalized();
-            
-        let tbn = Mat33::from_col_vec(tangent, bitangent, bn);
-        let tbn_inv = tbn.transposed();
+impl<'a> PbrShader<'a> {
+    fn new(lights: &'a [SceneLight], eye: Vec3f, trans_matrix: Mat44, mesh: &'a obj::Mesh, material: &'a MaterialSettings, texture_map: &'a image::Image<u8>, tangent_map: &'a image::Image<u8>) -> PbrShader<'a> {
+        PbrShader {
+            lights,
+            eye,
+            material,
+            trans_matrix,
+            mesh,
+            texture_map,
+            tangent_map,
+            metallic_map: None,
+            roughness_map: None,
+            occlusion_map: None,
+            emissive_map: None,
+            environment: None,
+            ambient_occlusion: None,
+            alpha_test: None,
+            two_sided: false,
+
+            varyings: SurfaceVaryings::default(),
+        }
+    }
 
-        let (nx, ny, nz) = texture(self.tangent_map, uv);
-        let get_normal_value = |c| {f32::from(c) / 255.0 * 2.0 - 1.0};
+    // Red channel of a grayscale map, white without a map
+    fn gray(map: Option<&image::Image<u8>>, uv: Vec2f) -> f32 {
+        map.map_or(1.0, |map| f32::from(texture(map, uv).0) / 255.0)
+    }
+}
 
-        let bn = Vec3f::new(get_normal_value(nx), get_normal_value(ny), get_normal_value(nz));
-        let light_dir_tangentspace = tbn_inv * self.light_dir;
+impl<'a> Shader for PbrShader<'a> {
 
-        let diffuse = bn.dot(light_dir_tangentspace).max(0.0);
+    fn vertex(&mut self, face_index: usize) -> (Vec4f, Vec4f, Vec4f) {
+        self.varyings = SurfaceVaryings::new(self.mesh, face_index);
+        self.varyings.transformed(self.trans_matrix)
+    }
 
-        let reflected_dir = (bn * (bn.dot(light_dir_tangentspace) * 2.0) - light_dir_tangentspace).normalized();
-        let (spec, _, _) = texture(self.spec_map, uv);
-        let spec = reflected_dir.z.max(0.0).powf(f32::from(spec));
+    fn fragment(&self, bar: Vec3f) -> Option<Vec4f> {
+        self.surface(bar).map(|sample| self.lighting(&sample))
+    }
 
-        let (r, g, b) = texture(self.texture_map, uv);
-        let (r, g, b) = (f32::from(r), f32::from(g), f32::from(b));
+    fn two_sided(&self) -> bool {
+        self.two_sided
+    }
+}
+
+impl<'a> DeferredShader for PbrShader<'a> {
+    fn surface(&self, bar: Vec3f) -> Option<GSample> {
 
-        let light_calc = |c: f32| -> u8 {
-            (c * shadow * (diffuse + 0.6 * spec)) as u8
+        let uv = self.varyings.uv(bar);
+        if self.alpha_test.is_some_and(|t| t.discard(uv)) {
+            return None;
+        }
+
+        let world_pos = self.varyings.world_pos(bar);
+        let (bn, normal_worldspace) = self.varyings.normals(bar, uv, self.tangent_map);
+
+        Some(GSample {
+            position: world_pos,
+            normal: normal_worldspace,
+            vertex_normal: bn,
+            albedo: texture_srgba(self.texture_map, uv),
+            metallic: self.material.metallic * PbrShader::gray(self.metallic_map, uv),
+            roughness: self.material.roughness * PbrShader::gray(self.roughness_map, uv),
+            occlusion: PbrShader::gray(self.occlusion_map, uv) * ambient_occlusion(self.ambient_occlusion, self.trans_matrix, world_pos),
+            emissive: match self.emissive_map {
+                Some(map) => self.material.emissive * texture_srgba(map, uv).xyz(),
+                None => self.material.emissive,
+            },
+            ..GSample::default()
+        })
+    }
+
+    fn lighting(&self, sample: &GSample) -> Vec4f {
+        let surface = Surface {
+            base_color: sample.albedo.xyz(),
+            metallic: sample.metallic,
+            roughness: sample.roughness,
+            occlusion: sample.occlusion,
+            emissive: sample.emissive,
         };
+        let n = sample.normal;
+        let v = (self.eye - sample.position).normalized();
+
+        let mut radiance = surface.emissive;
+        for light in self.lights.iter() {
+            radiance += match light.incident(sample.position, n) {
+                Incident::Direct { direction, radiance } => pbr::direct(&surface, n, v, direction, radiance),
+                Incident::Ambient(radiance) => pbr::ambient(&surface, n, v, radiance),
+            };
+        }
+        if let Some(environment) = self.environment {
+            let n_dot_v = n.dot(v);
+            let reflected = n * (2.0 * n_dot_v) - v;
+            let irradiance = environment.irradiance(n);
+            let prefiltered = environment.specular(reflected, surface.roughness);
+            radiance += pbr::environment(&surface, n, v, irradiance, prefiltered, environment.brdf(n_dot_v.max(0.0), surface.roughness));
+        }
+
+        Vec4f::from_vec3f(radiance, sample.albedo.w)
+    }
+}
+
+
 
-        (light_calc(r), light_calc(g), light_calc(b))
+// Fixed function state of a draw
+#[derive(Debug, Clone, Copy)]
+struct RenderState {
+    rasterizer: RasterizerState,
+    blend_mode: BlendMode,
+}
+
+impl Default for RenderState {
+    fn default() -> RenderState {
+        RenderState {
+            rasterizer: RasterizerState::default(),
+            blend_mode: BlendMode::Replace,
+        }
     }
 }
 
+// Where the fragments of a draw end up
+enum ColorTarget<'a> {
+    // Fragments are blended and written in submission order
+    Buffer(&'a mut Framebuffer<Vec4f>),
+    // Fragments are kept per pixel, without writing the z-buffer, and blended
+    // back to front once resolved
+    FragmentLists(&'a mut oit::FragmentLists),
+    // Only the z-buffer is written, fragments are still run for discards
+    DepthOnly,
+}
 
+// Pixel of a triangle where covered samples pass the depth test
+struct PixelFragment {
+    x: usize,
+    y: usize,
+    // Barycentric coordinates to shade the pixel with, in the triangle set up
+    // by the vertex stage, and the depth there
+    bar: Vec3f,
+    depth: f32,
+    // Bit `s` is set when sample `s` passes the depth test, at `depths[s]`
+    passed: u32,
+    depths: [f32; raster::MAX_SAMPLES],
+}
 
-fn render_mesh_shader(mesh: &obj::Mesh, shader: &mut Shader, z_buffer: &mut Vec<f32>, image: &mut ppm::Image) {
+impl PixelFragment {
+    // Samples passing the depth test, with their depth
+    fn passed_samples(&self) -> impl Iterator<Item = (usize, f32)> + '_ {
+        self.depths.iter().cloned().enumerate().filter(move |&(s, _)| self.passed & (1 << s) != 0)
+    }
+}
 
+// Clips, culls and rasterizes the triangles set up by the shader, testing the
+// depth of every covered sample. `write` gets the pixels where samples pass the
+// test, and writes the z-buffer itself.
+fn rasterize_mesh<S, F>(mesh: &obj::Mesh, shader: &mut S, state: &RenderState, z_buffer: &mut Framebuffer<f32>, mut write: F)
+where
+    S: Shader + ?Sized,
+    F: FnMut(&S, &mut Framebuffer<f32>, &PixelFragment),
+{
     for index in 0..mesh.faces.len() {
-        let (v1, v2, v3) = shader.vertex(index);
-        let (v1_hom, v2_hom, v3_hom) = (v1.homogenize(), v2.homogenize(), v3.homogenize());
+        let (c1, c2, c3) = shader.vertex(index);
 
-        let xmin = v1_hom.x.min(v2_hom.x.min(v3_hom.x)).max(0.0) as usize;
-        let ymin = v1_hom.y.min(v2_hom.y.min(v3_hom.y)).max(0.0) as usize;
-        let xmax = v1_hom.x.max(v2_hom.x.max(v3_hom.x)).min(FWIDTH - 1.0) as usize;
-        let ymax = v1_hom.y.max(v2_hom.y.max(v3_hom.y)).min(FHEIGHT - 1.0) as usize;
+        // Vertices behind the eye are clipped away, the fragments get the
+        // barycentric coordinates of the triangle the shader has set up
+        for [(v1, b1), (v2, b2), (v3, b3)] in raster::clip_triangle([c1, c2, c3]) {
+            let to_face_bar = |bar: Vec3f| b1 * bar.x + b2 * bar.y + b3 * bar.z;
+            let (v1_hom, v2_hom, v3_hom) = (v1.homogenize(), v2.homogenize(), v3.homogenize());
 
-        for y in  ymin..=ymax {
-            for x in xmin..=xmax {
-                let p = Vec2f::new(x as f32, y as f32);
-                let bar = barycenter(v1_hom.xy(), v2_hom.xy(), v3_hom.xy(), p);
+            if !shader.two_sided() && state.rasterizer.is_culled(v1_hom.xy(), v2_hom.xy(), v3_hom.xy()) {
+                continue;
+            }
 
-                if bar.x < 0.0 || bar.y < 0.0 || bar.z < 0.0 { continue; }
+            let depth_offset = state.rasterizer.depth_offset([v1_hom, v2_hom, v3_hom]);
+
+            let (width, height, samples) = (z_buffer.width, z_buffer.height, z_buffer.samples);
+            raster::rasterize_triangle([v1_hom.xy(), v2_hom.xy(), v3_hom.xy()], width, height, samples, |x, y, coverage| {
+                let depth_at = |bar: Vec3f| {
+                    let pos = v1 * bar.x + v2 * bar.y + v3 * bar.z;
+                    pos.z / pos.w - depth_offset
+                };
+
+                // Depth is tested per sample
+                let mut passed = 0_u32;
+                let mut depths = [0.0; raster::MAX_SAMPLES];
+                for (s, depth) in depths.iter_mut().enumerate().take(samples) {
+                    if coverage.mask & (1 << s) == 0 { continue; }
+                    *depth = depth_at(coverage.sample_bars[s]);
+                    if z_buffer.get_sample(x, y, s) <= *depth {
+                        passed |= 1 << s;
+                    }
+                }
+
+                if passed == 0 { return; }
+
+                let fragment = PixelFragment {
+                    x,
+                    y,
+                    bar: to_face_bar(coverage.bar),
+                    depth: depth_at(coverage.bar),
+                    passed,
+                    depths,
+                };
+                write(shader, z_buffer, &fragment);
+            });
+        }
+    }
+}
 
-                let pos = v1 * bar.x + v2 * bar.y + v3 * bar.z;
-                let fragment_depth = pos.z / pos.w;
-                let zb = &mut z_buffer[(y * WIDTH) + x];
+fn render_mesh_shader(mesh: &obj::Mesh, shader: &mut dyn Shader, state: &RenderState, z_buffer: &mut Framebuffer<f32>, target: &mut ColorTarget) {
+    if let ColorTarget::Buffer(color_buffer) = target {
+        assert_eq!(color_buffer.samples, z_buffer.samples);
+    }
 
-                if *zb > fragment_depth { continue; }
-                *zb = fragment_depth;
+    let samples = z_buffer.samples;
+    rasterize_mesh(mesh, shader, state, z_buffer, |shader, z_buffer, fragment| {
+        // The fragment is shaded once per pixel
+        let color = match shader.fragment(fragment.bar) {
+            Some(color) => color,
+            None => return,
+        };
 
-                let (r, g, b) = shader.fragment(bar);
-                image.set(x, y, ppm::RGB::new(r, g, b));
+        match target {
+            ColorTarget::Buffer(color_buffer) => {
+                for (s, depth) in fragment.passed_samples() {
+                    *z_buffer.get_sample_mut(fragment.x, fragment.y, s) = depth;
+                    let dst = color_buffer.get_sample_mut(fragment.x, fragment.y, s);
+                    *dst = state.blend_mode.blend(color, *dst);
+                }
+            }
+            ColorTarget::FragmentLists(lists) => {
+                // Partially covered pixels are turned into transparency
+                let coverage_ratio = fragment.passed.count_ones() as f32 / samples as f32;
+                let color = Vec4f::from_vec3f(color.xyz(), color.w * coverage_ratio);
+                lists.push(fragment.x, fragment.y, color, fragment.depth, state.blend_mode);
+            }
+            ColorTarget::DepthOnly => {
+                for (s, depth) in fragment.passed_samples() {
+                    *z_buffer.get_sample_mut(fragment.x, fragment.y, s) = depth;
+                }
             }
         }
+    });
+}
+
+// Geometry pass of the deferred shading: the surfaces of the fragments are
+// written to the G-buffer in place of their colors
+fn render_mesh_surfaces(mesh: &obj::Mesh, shader: &mut dyn DeferredShader, state: &RenderState, z_buffer: &mut Framebuffer<f32>, g_buffer: &mut GBuffer) {
+    assert_eq!(g_buffer.samples, z_buffer.samples);
+
+    rasterize_mesh(mesh, shader, state, z_buffer, |shader, z_buffer, fragment| {
+        let sample = match shader.surface(fragment.bar) {
+            Some(sample) => sample,
+            None => return,
+        };
+        for (s, depth) in fragment.passed_samples() {
+            *z_buffer.get_sample_mut(fragment.x, fragment.y, s) = depth;
+            *g_buffer.get_sample_mut(fragment.x, fragment.y, s) = sample;
+        }
+    });
+}
+
+// Lighting pass of the deferred shading: the samples of the G-buffer still at
+// `clear_depth` are left untouched, the others are lit by the shader and
+// written to the color buffer. Samples of a pixel written by the same fragment
+// share its lighting, as in the forward path.
+fn render_deferred_lighting(shader: &dyn DeferredShader, z_buffer: &Framebuffer<f32>, g_buffer: &GBuffer, clear_depth: f32, color_buffer: &mut Framebuffer<Vec4f>) {
+    assert_eq!(color_buffer.samples, z_buffer.samples);
+    assert_eq!(g_buffer.samples, z_buffer.samples);
+
+    for y in 0..z_buffer.height {
+        for x in 0..z_buffer.width {
+            let mut shaded: Option<(Vec3f, Vec4f)> = None;
+            for s in 0..z_buffer.samples {
+                if z_buffer.get_sample(x, y, s) == clear_depth {
+                    continue;
+                }
+                let sample = g_buffer.get_sample(x, y, s);
+                let color = match shaded {
+                    Some((position, color)) if position == sample.position => color,
+                    _ => BlendMode::Replace.blend(shader.lighting(&sample), Vec4f::default()),
+                };
+                shaded = Some((sample.position, color));
+                *color_buffer.get_sample_mut(x, y, s) = color;
+            }
+        }
+    }
+}
+
+// Line drawing state of a wireframe draw
+#[derive(Debug, Clone, Copy)]
+struct LineStyle {
+    // Linear color, with straight alpha
+    color: Vec4f,
+    width: f32,
+}
+
+// Draws the edges of the triangles that are not culled, with the positions
+// given by the shader vertex stage. Edges shared by several triangles are
+// only drawn once.
+fn render_mesh_wireframe(mesh: &obj::Mesh, shader: &mut dyn Shader, state: &RenderState, style: &LineStyle, z_buffer: &mut Framebuffer<f32>, color_buffer: &mut Framebuffer<Vec4f>) {
+    assert_eq!(color_buffer.samples, z_buffer.samples);
+
+    let mut drawn_edges = HashSet::new();
+    for index in 0..mesh.faces.len() {
+        let (v1, v2, v3) = shader.vertex(index);
+        let (v1_hom, v2_hom, v3_hom) = (v1.homogenize(), v2.homogenize(), v3.homogenize());
+
+        if !shader.two_sided() && state.rasterizer.is_culled(v1_hom.xy(), v2_hom.xy(), v3_hom.xy()) {
+            continue;
+        }
+
+        let face = &mesh.faces[index];
+        let vertices = [(face[0].0, v1), (face[1].0, v2), (face[2].0, v3)];
+        for i in 0..3 {
+            let (a, va) = vertices[i];
+            let (b, vb) = vertices[(i + 1) % 3];
+            if !drawn_edges.insert((a.min(b), a.max(b))) {
+                continue;
+            }
+
+            let (width, height, samples) = (z_buffer.width, z_buffer.height, z_buffer.samples);
+            let ends = [va.homogenize().xy(), vb.homogenize().xy()];
+            raster::rasterize_line(ends, style.width, width, height, samples, |x, y, coverage| {
+                for s in 0..samples {
+                    if coverage.mask & (1 << s) == 0 { continue; }
+                    let bar = coverage.sample_bars[s];
+                    let pos = va * bar.x + vb * bar.y;
+                    let depth = pos.z / pos.w + LINE_DEPTH_BIAS;
+                    if z_buffer.get_sample(x, y, s) <= depth {
+                        *z_buffer.get_sample_mut(x, y, s) = depth;
+                        let dst = color_buffer.get_sample_mut(x, y, s);
+                        *dst = state.blend_mode.blend(style.color, *dst);
+                    }
+                }
+            });
+        }
     }
 }
 
+fn mesh_bounds(mesh: &obj::Mesh) -> (Vec3f, Vec3f) {
+    let mut min = Vec3f::new(f32::MAX, f32::MAX, f32::MAX);
+    let mut max = Vec3f::new(f32::MIN, f32::MIN, f32::MIN);
+    for v in mesh.vertices.iter() {
+        min = Vec3f::new(min.x.min(v.x), min.y.min(v.y), min.z.min(v.z));
+        max = Vec3f::new(max.x.max(v.x), max.y.max(v.y), max.z.max(v.z));
+    }
+    (min, max)
+}
+
 fn render_scene(
     filename: &str,
     texture_name: &str,
     normal_map_name: &str,
     tangent_map_name: &str,
     specular_map_name: &str,
-    image: &mut ppm::Image,
-) -> std::io::Result<()> {
-
-    let mut z_buffer = vec![std::f32::MIN; image.width * image.height];
+    config: &RenderConfig,
+    color_buffer: &mut Framebuffer<Vec4f>,
+) -> std::io::Result<usize> {
 
     let mut resource_dir = std::env::current_dir().unwrap();
     resource_dir.push("rsrc");
@@ -401,12 +796,35 @@ fn render_scene(
         let mut path = resource_dir.clone();
         path.push(n);
         match image::load(path.as_path()) {
-            Error(str) => panic!(str),
+            Error(str) => panic!("{}", str),
             ImageU8(image) => image,
             ImageF32(_image) => panic!("Wrong image format"),
         }
     };
 
+    let load_hdr_image_with_name = |n: &str| -> image::Image<f32> {
+        println!("Loading {}", n);
+        let mut path = resource_dir.clone();
+        path.push(n);
+        match image::load(path.as_path()) {
+            Error(str) => panic!("{}", str),
+            ImageU8(_image) => panic!("Environment maps must be HDR images"),
+            ImageF32(image) => image,
+        }
+    };
+
+    // Linear map from a HDR or sRGB image
+    let load_sky_map_with_name = |n: &str| -> Framebuffer<Vec3f> {
+        println!("Loading {}", n);
+        let mut path = resource_dir.clone();
+        path.push(n);
+        match image::load(path.as_path()) {
+            Error(str) => panic!("{}", str),
+            ImageU8(image) => sky::from_srgb_image(&image),
+            ImageF32(image) => ibl::from_hdr_image(&image),
+        }
+    };
+
     let texture_map = load_image_with_name(texture_name);
     let _normal_map = load_image_with_name(normal_map_name);
     let tangent_map = load_image_with_name(tangent_map_name);
@@ -430,46 +848,314 @@ fn render_scene(
     let eye = Vec3f::new(1.0, 1.0, 4.0);
     let center = Vec3f::new(0.0, 0.0, 0.0);
     let up = Vec3f::new(0.0, 1.0, 0.0);
-    let light_dir_worldspace = Vec3f::new(1.0, 1.0, 0.0).normalized();
 
     let camera_from_world = Mat44::lookat(eye, center, up);
-    let view_from_camera = Mat44::projection(-1.0 / (eye - center).length());
-    let screen_from_view = Mat44::viewport( FWIDTH / 8.0, FHEIGHT / 8.0, (FWIDTH * 3.0) / 4.0, (FHEIGHT * 3.0) / 4.0, MAX_DEPTH,);
+    let view = ViewFrustum {
+        camera_from_world,
+        projection_coef: -1.0 / (eye - center).length(),
+        // The viewport maps [-1, 1] to the central 3/4 of the screen
+        ndc_extent: 4.0 / 3.0,
+    };
+    let view_from_camera = Mat44::projection(view.projection_coef);
+    let (width, height) = (color_buffer.width as f32, color_buffer.height as f32);
+    let screen_from_view = Mat44::viewport( width / 8.0, height / 8.0, (width * 3.0) / 4.0, (height * 3.0) / 4.0, MAX_DEPTH,);
 
     let screen_from_world = screen_from_view * view_from_camera * camera_from_world;
 
 
-    let lightcamera_from_world = Mat44::lookat(light_dir_worldspace, center, up);
-    let lightview_from_lightcamera = Mat44::projection(0.0);
-    let lightport_from_lightview = screen_from_view;
+    let alpha_test = config.alpha_cutoff.map(|cutoff| AlphaTest { alpha_map: &texture_map, cutoff });
 
-    let mut depth_shader = DepthShader::new(lightport_from_lightview * lightview_from_lightcamera * lightcamera_from_world, &mesh);
+    // Depth of the mesh seen from the camera, without shading it
+    let depth_state = RenderState { rasterizer: config.rasterizer, ..RenderState::default() };
+    let render_camera_depth = |z_buffer: &mut Framebuffer<f32>| {
+        let mut depth_shader = DepthShader::new(screen_from_world, &mesh);
+        depth_shader.alpha_test = alpha_test;
+        depth_shader.two_sided = config.two_sided;
+        render_mesh_shader(&mesh, &mut depth_shader, &depth_state, z_buffer, &mut ColorTarget::DepthOnly);
+    };
 
-    render_mesh_shader(&mesh, &mut depth_shader, &mut z_buffer, image);
+    // Depth pre-pass of the occlusion, at one sample per pixel
+    let ambient_occlusion = if config.ssao.enabled && (config.render_mode != RenderMode::Wireframe || config.ssao.debug) {
+        let mut depth = Framebuffer::new(color_buffer.width, color_buffer.height, f32::MIN);
+        render_camera_depth(&mut depth);
+        Some(AmbientOcclusion::new(&config.ssao, &depth, screen_from_view * view_from_camera, f32::MIN))
+    } else {
+        None
+    };
+    // The raw occlusion, `render_image` writes it without tone mapping. The
+    // lights, materials and environment aren't loaded for it
+    if let (Some(ao), true) = (&ambient_occlusion, config.ssao.debug) {
+        for y in 0..color_buffer.height {
+            for x in 0..color_buffer.width {
+                let a = ao.buffer.get(x, y);
+                color_buffer.set(x, y, Vec4f::new(a, a, a, 1.0));
+            }
+        }
+        return Ok(mesh.faces.len());
+    }
 
-    let depth_map = z_buffer.clone();
+    let shadow_state = RenderState {
+        rasterizer: RasterizerState {
+            depth_bias: config.shadow.depth_bias,
+            slope_scaled_depth_bias: config.shadow.slope_scaled_depth_bias,
+            ..config.rasterizer
+        },
+        ..RenderState::default()
+    };
+    let render_shadow_depth = |map: &mut ShadowMap| {
+        let mut depth_shader = DepthShader::new(map.light_from_world, &mesh);
+        depth_shader.alpha_test = alpha_test;
+        depth_shader.two_sided = config.two_sided;
+        render_mesh_shader(&mesh, &mut depth_shader, &shadow_state, &mut map.depth, &mut ColorTarget::DepthOnly);
+    };
+    let shadow_pass = config.render_mode != RenderMode::Wireframe;
+
+    // Shadow maps of the direct lights
+    let scene_bounds = mesh_bounds(&mesh);
+    let mut lights = Vec::with_capacity(config.lights.len());
+    for light in config.lights.iter() {
+        let mut shadow = match light {
+            Light::Directional(light) => {
+                let settings = ShadowSettings {
+                    filter: light.shadow_filter.unwrap_or(config.shadow.filter),
+                    ..config.shadow
+                };
+                LightShadow::Cascaded(CascadedShadowMap::new(&settings, view, light.direction, scene_bounds, MAX_DEPTH))
+            }
+            Light::Point(light) => {
+                let mut cube_map = CubeShadowMap::new(config.shadow.resolution, light.position, LIGHT_SHADOW_NEAR, MAX_DEPTH);
+                cube_map.filter = light.shadow_filter.unwrap_or(config.shadow.filter);
+                LightShadow::Cube(cube_map)
+            }
+            Light::Spot(light) => {
+                let mut shadow_map = ShadowMap::perspective(config.shadow.resolution, light.clip_from_world(LIGHT_SHADOW_NEAR), MAX_DEPTH);
+                shadow_map.filter = light.shadow_filter.unwrap_or(config.shadow.filter);
+                LightShadow::Perspective(shadow_map)
+            }
+            _ => LightShadow::None,
+        };
+        if shadow_pass {
+            for map in shadow.maps_mut() {
+                render_shadow_depth(map);
+                map.prefilter();
+            }
+        }
 
-    let mut z_buffer = vec![std::f32::MIN; image.width * image.height];
-    let mut phongd_shader = PhongDShader::new(light_dir_worldspace, screen_from_world, screen_from_world.inverse(), lightport_from_lightview, &mesh, &texture_map, &spec_map, &tangent_map, &depth_map);
-    render_mesh_shader(&mesh, &mut phongd_shader, &mut z_buffer, image);
+        let cookie = match light {
+            Light::Spot(spot) => spot.cookie.as_ref().map(|name| Cookie {
+                image: load_image_with_name(name),
+                clip_from_world: spot.clip_from_world(LIGHT_SHADOW_NEAR),
+            }),
+            _ => None,
+        };
+        lights.push(SceneLight { light: light.clone(), shadow, cookie });
+    }
 
+    let samples = config.msaa_samples;
+    let mut z_buffer = Framebuffer::new_multisample(color_buffer.width, color_buffer.height, samples, f32::MIN);
+    let mut scene_buffer = color_buffer.multisampled(samples);
+
+    let material = &config.material;
+    let metallic_map = material.metallic_map.as_ref().map(|name| load_image_with_name(name));
+    let roughness_map = material.roughness_map.as_ref().map(|name| load_image_with_name(name));
+    let occlusion_map = material.occlusion_map.as_ref().map(|name| load_image_with_name(name));
+    let emissive_map = material.emissive_map.as_ref().map(|name| load_image_with_name(name));
+
+    let environment_image = config.environment.as_ref().map(|name| load_hdr_image_with_name(name));
+    let environment = environment_image.as_ref().map(|image| {
+        let mut environment = Environment::new(image);
+        environment.intensity = config.environment_intensity;
+        environment
+    });
+
+    let sky = config.sky.as_ref().map(|sky| match sky {
+        SkySettings::Environment => {
+            // As bright as the lighting of the environment
+            let mut map = ibl::from_hdr_image(environment_image.as_ref().unwrap());
+            for y in 0..map.height {
+                for x in 0..map.width {
+                    *map.get_mut(x, y) = map.get(x, y) * config.environment_intensity;
+                }
+            }
+            Sky::Map(map)
+        }
+        SkySettings::Map(name) => Sky::Map(load_sky_map_with_name(name)),
+        SkySettings::Cube(names) => Sky::Cube(names.iter().map(|name| load_sky_map_with_name(name)).collect()),
+        SkySettings::Gradient { zenith, horizon, ground } => Sky::Gradient { zenith: *zenith, horizon: *horizon, ground: *ground },
+    });
+
+    let mut phongd_shader = PhongDShader::new(&lights, screen_from_world, &mesh, &texture_map, &spec_map, &tangent_map);
+    phongd_shader.alpha_test = alpha_test;
+    phongd_shader.two_sided = config.two_sided;
+    phongd_shader.environment = environment.as_ref();
+    phongd_shader.ambient_occlusion = ambient_occlusion.as_ref();
+    let mut pbr_shader = PbrShader::new(&lights, eye, screen_from_world, &mesh, material, &texture_map, &tangent_map);
+    pbr_shader.metallic_map = metallic_map.as_ref();
+    pbr_shader.roughness_map = roughness_map.as_ref();
+    pbr_shader.occlusion_map = occlusion_map.as_ref();
+    pbr_shader.emissive_map = emissive_map.as_ref();
+    pbr_shader.alpha_test = alpha_test;
+    pbr_shader.two_sided = config.two_sided;
+    pbr_shader.environment = environment.as_ref();
+    pbr_shader.ambient_occlusion = ambient_occlusion.as_ref();
+    let shader: &mut dyn DeferredShader = match config.shading {
+        ShadingModel::Phong => &mut phongd_shader,
+        ShadingModel::Pbr => &mut pbr_shader,
+    };
+    let state = RenderState {
+        rasterizer: config.rasterizer,
+        blend_mode: config.blend_mode,
+    };
 
-    Ok(())
-}
+    // The sky only shades the samples the opaque geometry leaves clear, but
+    // goes under the geometry blended in submission order
+    let render_sky = |z_buffer: &Framebuffer<f32>, scene_buffer: &mut Framebuffer<Vec4f>| {
+        if let Some(ref sky) = sky {
+            sky.render(screen_from_world, eye, center, f32::MIN, z_buffer, scene_buffer);
+        }
+    };
+    let sky_first = config.blend_mode != BlendMode::Replace && !config.order_independent;
+    if sky_first {
+        render_sky(&z_buffer, &mut scene_buffer);
+    }
 
-fn main() -> std::io::Result<()> {
-    let mut image = ppm::Image::new(WIDTH, HEIGHT);
+    if config.render_mode != RenderMode::Wireframe {
+        if config.order_independent {
+            let mut lists = oit::FragmentLists::new(color_buffer.width, color_buffer.height);
+            render_mesh_shader(&mesh, shader, &state, &mut z_buffer, &mut ColorTarget::FragmentLists(&mut lists));
+            render_sky(&z_buffer, &mut scene_buffer);
+            lists.resolve(&mut scene_buffer);
+        } else if config.deferred {
+            let mut g_buffer = Framebuffer::new_multisample(color_buffer.width, color_buffer.height, samples, GSample::default());
+            render_mesh_surfaces(&mesh, shader, &state, &mut z_buffer, &mut g_buffer);
+            render_deferred_lighting(shader, &z_buffer, &g_buffer, f32::MIN, &mut scene_buffer);
+            render_sky(&z_buffer, &mut scene_buffer);
+        } else {
+            render_mesh_shader(&mesh, shader, &state, &mut z_buffer, &mut ColorTarget::Buffer(&mut scene_buffer));
+            if !sky_first {
+                render_sky(&z_buffer, &mut scene_buffer);
+            }
+        }
+    } else {
+        if !sky_first {
+            render_sky(&z_buffer, &mut scene_buffer);
+        }
+        // The lines are still hidden by the surface of the mesh
+        render_camera_depth(&mut z_buffer);
+    }
 
-    render_scene(
-        "african_head.obj",
+    if config.render_mode != RenderMode::Shaded {
+        let line_state = RenderState {
+            rasterizer: config.rasterizer,
+            blend_mode: BlendMode::Alpha,
+        };
+        let style = LineStyle {
+            color: config.line_color,
+            width: config.line_width,
+        };
+        render_mesh_wireframe(&mesh, shader, &line_state, &style, &mut z_buffer, &mut scene_buffer);
+    }
+
+    *color_buffer = scene_buffer.resolve();
+
+    Ok(mesh.faces.len())
+}
+
+// Renders the scene into an HDR buffer and the tone-mapped output image, both
+// at the output resolution. Returns the triangle count of the scene too.
+fn render_image(config: &RenderConfig) -> std::io::Result<(Framebuffer<Vec4f>, ppm::Image, usize)> {
+    let background = config.background;
+    let background = Vec4f::from_vec3f(background.xyz() * background.w, background.w);
+    // Supersampling renders at a higher resolution, and filters the result down
+    let scale = config.supersampling;
+    let mut color_buffer = Framebuffer::new(WIDTH * scale, HEIGHT * scale, background);
+
+    let triangles = render_scene(
+        MODEL_NAME,
         "african_head_diffuse.tga",
         "african_head_nm.tga",
         "african_head_nm_tangent.tga",
         "african_head_spec.tga",
-        &mut image,
+        config,
+        &mut color_buffer,
     )?;
 
-    println!("opening the output");
+    let mut image = ppm::Image::new(color_buffer.width, color_buffer.height);
+    if config.ssao.debug {
+        // Occlusion values are written as they are, without exposure or
+        // sRGB encoding
+        for y in 0..color_buffer.height {
+            for x in 0..color_buffer.width {
+                image.set(x, y, ppm::RGB::grey(color_buffer.get(x, y).x.clamp(0.0, 1.0)));
+            }
+        }
+    } else {
+        config.tone_mapping.apply(&color_buffer, &mut image);
+    }
+    if scale > 1 {
+        image = image.resample(WIDTH, HEIGHT, config.resample_filter);
+
+        // The negative lobes of the filters can ring below 0, which the float
+        // outputs would keep
+        let hdr = resample::resample(color_buffer.data(), color_buffer.width, color_buffer.height, WIDTH, HEIGHT, config.resample_filter);
+        color_buffer = Framebuffer::new(WIDTH, HEIGHT, Vec4f::default());
+        for (i, c) in hdr.into_iter().enumerate() {
+            color_buffer.set(i % WIDTH, i / WIDTH, Vec4f::new(c.x.max(0.0), c.y.max(0.0), c.z.max(0.0), c.w.clamp(0.0, 1.0)));
+        }
+    }
+
+    Ok((color_buffer, image, triangles))
+}
+
+// Renders the scene with each shadow filter, with the parameters of the
+// configuration, in a 2x2 grid of labelled tiles
+fn render_shadow_comparison(config: &RenderConfig) -> std::io::Result<ppm::Image> {
+    let parameters = config.shadow.filter_parameters;
+    let filters = [
+        (format!("PCF {0}x{0}", parameters.pcf_kernel), ShadowFilter::Pcf { kernel: parameters.pcf_kernel }),
+        (String::from("PCSS"), ShadowFilter::Pcss { light_size: parameters.light_size }),
+        (
+            String::from("VSM"),
+            ShadowFilter::Variance { blur_radius: parameters.blur_radius, light_bleeding: parameters.light_bleeding },
+        ),
+        (
+            String::from("ESM"),
+            ShadowFilter::Exponential { blur_radius: parameters.blur_radius, exponent: parameters.esm_exponent },
+        ),
+    ];
+
+    let (tile_width, tile_height) = (WIDTH / 2, HEIGHT / 2);
+    let mut grid = ppm::Image::new(WIDTH, HEIGHT);
+    for (i, (name, filter)) in filters.iter().enumerate() {
+        // Only the directional lights support every filter, the other lights
+        // keep theirs
+        let mut tile_config = config.clone();
+        for light in tile_config.lights.iter_mut() {
+            if let Light::Directional(light) = light {
+                light.shadow_filter = Some(*filter);
+            }
+        }
+
+        let start = Instant::now();
+        let (_, image, _) = render_image(&tile_config)?;
+        let mut tile = image.resample(tile_width, tile_height, config.resample_filter);
+
+        let mut style = text::TextStyle::new(ppm::RGB::white());
+        style.background = Some(ppm::RGB::new_rgba(0, 0, 0, 160));
+        let label = format!("{}\n{} ms", name, start.elapsed().as_millis());
+        tile.draw_text(6, tile_height as i32 - 7, &label, &style);
+
+        let (column, row) = (i % 2, i / 2);
+        grid.blit(&tile, (column * tile_width) as i32, ((1 - row) * tile_height) as i32);
+    }
+
+    Ok(grid)
+}
+
+fn main() -> std::io::Result<()> {
+    let config = RenderConfig::from_args(std::env::args().skip(1))
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+
     let mut output_dir = std::env::current_dir().unwrap();
     output_dir.push("output");
 
@@ -478,12 +1164,154 @@ fn main() -> std::io::Result<()> {
         .create(output_dir.as_path())
         .unwrap();
 
-    output_dir.push("result.ppm");
-    let mut file = File::create(output_dir.as_path())?;
+    if config.compare_shadows {
+        let image = render_shadow_comparison(&config)?;
+        let mut file = File::create(output_dir.join("shadow_comparison.ppm").as_path())?;
+        file.write_all(String::from(&image).as_bytes())?;
+        println!("Done!");
+        return Ok(());
+    }
+
+    let start = Instant::now();
+    let (color_buffer, mut image, triangles) = render_image(&config)?;
+    let render_time = start.elapsed();
+
+    let mut label: Vec<String> = config.label.iter().cloned().collect();
+    if config.stats {
+        label.push(String::from(MODEL_NAME));
+        label.push(format!("{} triangles", triangles));
+        label.push(format!("{} ms", render_time.as_millis()));
+    }
+    if !label.is_empty() {
+        let mut style = text::TextStyle::new(ppm::RGB::white());
+        style.scale = config.label_scale;
+        style.background = Some(ppm::RGB::new_rgba(0, 0, 0, 160));
+        let margin = 4 + style.padding as i32;
+        image.draw_text(margin, image.height as i32 - 1 - margin, &label.join("\n"), &style);
+    }
 
     println!("Writing to output");
+
+    let mut file = File::create(output_dir.join("result.ppm").as_path())?;
     file.write_all(String::from(&image).as_bytes())?;
 
+    let mut file = BufWriter::new(File::create(output_dir.join("result.pam").as_path())?);
+    ppm::write_pam(&mut file, &image)?;
+
+    let mut file = BufWriter::new(File::create(output_dir.join("result.hdr").as_path())?);
+    hdr::write_radiance(&mut file, &color_buffer)?;
+
+    let mut file = BufWriter::new(File::create(output_dir.join("result.pfm").as_path())?);
+    hdr::write_pfm(&mut file, &color_buffer)?;
+
     println!("Done!");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use light::DirectionalLight;
+
+    // Renders the mesh with the shader through the forward and the deferred
+    // paths, from a marker clear color
+    fn render_forward_and_deferred(mesh: &obj::Mesh, shader: &mut dyn DeferredShader, samples: usize) -> [(Framebuffer<f32>, Framebuffer<Vec4f>); 2] {
+        let clear = Vec4f::new(0.25, 0.5, 0.75, 1.0);
+        let state = RenderState::default();
+
+        let mut forward_z = Framebuffer::new_multisample(8, 8, samples, f32::MIN);
+        let mut forward_color = Framebuffer::new_multisample(8, 8, samples, clear);
+        render_mesh_shader(mesh, shader, &state, &mut forward_z, &mut ColorTarget::Buffer(&mut forward_color));
+
+        let mut deferred_z = Framebuffer::new_multisample(8, 8, samples, f32::MIN);
+        let mut deferred_color = Framebuffer::new_multisample(8, 8, samples, clear);
+        let mut g_buffer = Framebuffer::new_multisample(8, 8, samples, GSample::default());
+        render_mesh_surfaces(mesh, shader, &state, &mut deferred_z, &mut g_buffer);
+        render_deferred_lighting(shader, &deferred_z, &g_buffer, f32::MIN, &mut deferred_color);
+
+        [(forward_z, forward_color), (deferred_z, deferred_color)]
+    }
+
+    #[test]
+    fn deferred_shading_test() {
+        let mesh = obj::Mesh::load("v -0.9 -0.7 0.0\nv 0.8 -0.9 0.2\nv 0.1 0.9 -0.2\n\
+                                    vt  0.0 0.0 0.0\nvt  1.0 0.0 0.0\nvt  0.5 1.0 0.0\n\
+                                    vn  -0.3 0.0 1.0\nvn  0.3 -0.2 1.0\nvn  0.0 0.3 1.0\n\
+                                    f 1/1/1 2/2/2 3/3/3");
+        let rgb = |data: Vec<u8>| image::Image { width: 2, height: 2, depth: 3, data };
+        let texture_map = rgb(vec![200, 40, 40, 40, 200, 40, 40, 40, 200, 200, 200, 40]);
+        let spec_map = rgb(vec![20; 12]);
+        let tangent_map = rgb([128, 128, 255].iter().cloned().cycle().take(12).collect());
+        let lights = [
+            SceneLight { light: Light::Directional(DirectionalLight::new(Vec3f::new(-0.5, -0.5, -1.0))), shadow: LightShadow::None, cookie: None },
+            SceneLight { light: Light::Ambient { color: Vec3f::new(1.0, 1.0, 1.0), intensity: 0.2 }, shadow: LightShadow::None, cookie: None },
+        ];
+        let material = MaterialSettings::default();
+        let viewport = Mat44::viewport(0.0, 0.0, 8.0, 8.0, MAX_DEPTH);
+
+        let mut phongd_shader = PhongDShader::new(&lights, viewport, &mesh, &texture_map, &spec_map, &tangent_map);
+        phongd_shader.two_sided = true;
+        let mut pbr_shader = PbrShader::new(&lights, Vec3f::new(0.0, 0.0, 3.0), viewport, &mesh, &material, &texture_map, &tangent_map);
+        pbr_shader.two_sided = true;
+        let shaders: [&mut dyn DeferredShader; 2] = [&mut phongd_shader, &mut pbr_shader];
+
+        for shader in shaders {
+            let [(forward_z, forward_color), (deferred_z, deferred_color)] = render_forward_and_deferred(&mesh, shader, 4);
+            assert_eq!(forward_z.data(), deferred_z.data());
+
+            // Samples the triangle doesn't cover keep the clear color
+            let clear = Vec4f::new(0.25, 0.5, 0.75, 1.0);
+            let covered = deferred_z.data().iter().filter(|&&z| z != f32::MIN).count();
+            assert!(covered > 0 && covered < deferred_z.data().len());
+            for (&z, color) in deferred_z.data().iter().zip(deferred_color.data()) {
+                if z == f32::MIN {
+                    assert_eq!((color.x, color.y, color.z, color.w), (clear.x, clear.y, clear.z, clear.w));
+                }
+            }
+
+            for (forward, deferred) in forward_color.resolve().data().iter().zip(deferred_color.resolve().data()) {
+                assert_eq!((forward.x, forward.y, forward.z, forward.w), (deferred.x, deferred.y, deferred.z, deferred.w));
+            }
+        }
+    }
+
+    #[test]
+    fn cutout_test() {
+        let mesh = obj::Mesh::load("v -0.9 -0.7 0.0\nv 0.8 -0.9 0.2\nv 0.1 0.9 -0.2\n\
+                                    vt  0.0 0.0 0.0\nvt  1.0 0.0 0.0\nvt  0.5 1.0 0.0\n\
+                                    vn  0.0 0.0 1.0\nvn  0.0 0.0 1.0\nvn  0.0 0.0 1.0\n\
+                                    f 1/1/1 2/2/2 3/3/3");
+        // Transparent on the left half, opaque on the right half
+        let texture_map = image::Image { width: 2, height: 2, depth: 4, data: vec![200, 40, 40, 0, 40, 200, 40, 255, 200, 40, 40, 0, 40, 200, 40, 255] };
+        let spec_map = image::Image { width: 1, height: 1, depth: 3, data: vec![20; 3] };
+        let tangent_map = image::Image { width: 1, height: 1, depth: 3, data: vec![128, 128, 255] };
+        let lights = [SceneLight { light: Light::Ambient { color: Vec3f::new(1.0, 1.0, 1.0), intensity: 1.0 }, shadow: LightShadow::None, cookie: None }];
+        let viewport = Mat44::viewport(0.0, 0.0, 8.0, 8.0, MAX_DEPTH);
+        let clear = Vec4f::new(0.25, 0.5, 0.75, 1.0);
+
+        let render = |alpha_test: Option<AlphaTest>| {
+            let mut shader = PhongDShader::new(&lights, viewport, &mesh, &texture_map, &spec_map, &tangent_map);
+            shader.alpha_test = alpha_test;
+            shader.two_sided = true;
+            let mut z_buffer = Framebuffer::new_multisample(8, 8, 4, f32::MIN);
+            let mut color_buffer = Framebuffer::new_multisample(8, 8, 4, clear);
+            render_mesh_shader(&mesh, &mut shader, &RenderState::default(), &mut z_buffer, &mut ColorTarget::Buffer(&mut color_buffer));
+            (z_buffer, color_buffer)
+        };
+        let (opaque_z, _) = render(None);
+        let (z_buffer, color_buffer) = render(Some(AlphaTest { alpha_map: &texture_map, cutoff: 0.5 }));
+
+        let covered = z_buffer.data().iter().filter(|&&z| z != f32::MIN).count();
+        let discarded = opaque_z.data().iter().zip(z_buffer.data()).filter(|&(&opaque, &z)| opaque != f32::MIN && z == f32::MIN).count();
+        assert!(covered > 0 && discarded > 0);
+
+        // Discarded samples write neither depth nor color
+        for ((&opaque, &z), color) in opaque_z.data().iter().zip(z_buffer.data()).zip(color_buffer.data()) {
+            if z == f32::MIN {
+                assert_eq!((color.x, color.y, color.z, color.w), (clear.x, clear.y, clear.z, clear.w));
+            } else {
+                assert_eq!(z, opaque);
+            }
+        }
+    }
+}
@@ -1,5 +1,6 @@
 use std::ops::{Add, AddAssign, Index, IndexMut, Mul, Neg, Sub};
 
+#[allow(dead_code)]
 const EPSILON: f32 = 0.001;
 
 #[derive(Clone, Copy, Default)]
@@ -57,6 +58,16 @@ impl Vec2f {
     pub fn new(x: f32, y: f32) -> Vec2f {
         Vec2f { x, y }
     }
+
+    #[inline(always)]
+    pub fn length(self) -> f32 {
+        self.dot(self).sqrt()
+    }
+
+    #[inline(always)]
+    pub fn dot(self, v: Vec2f) -> f32 {
+        self.x * v.x + self.y * v.y
+    }
 }
 
 impl Add<Vec2f> for Vec2f {
@@ -80,7 +91,7 @@ impl Mul<f32> for Vec2f {
     }
 }
 
-#[derive(Debug, Clone, Copy, Default)]
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
 pub struct Vec3f {
     pub x: f32,
     pub y: f32,
@@ -133,7 +144,7 @@ impl Add<Vec3f> for Vec3f {
 
 impl AddAssign<Vec3f> for Vec3f {
     fn add_assign(&mut self, v: Vec3f) {
-        *self = Vec3f::new(self.x + v.x, self.y + v.y, self.z + self.z);
+        *self = Vec3f::new(self.x + v.x, self.y + v.y, self.z + v.z);
     }
 }
 
@@ -164,6 +175,16 @@ impl Mul<f32> for Vec3f {
     }
 }
 
+// Component-wise product
+impl Mul<Vec3f> for Vec3f {
+    type Output = Vec3f;
+
+    #[inline(always)]
+    fn mul(self, v: Vec3f) -> Vec3f {
+        Vec3f::new(self.x * v.x, self.y * v.y, self.z * v.z)
+    }
+}
+
 impl Index<usize> for Vec3f {
     type Output = f32;
     fn index(&self, c: usize) -> &f32 {
@@ -219,7 +240,7 @@ impl Vec4f {
 
     #[allow(dead_code)]
     pub fn dot(self, v: Vec4f) -> f32 {
-        (self.x * v.x + self.y * v.y + self.z * v.z + self.w * v.w)
+        self.x * v.x + self.y * v.y + self.z * v.z + self.w * v.w
     }
 
     #[allow(dead_code)]
@@ -318,6 +339,7 @@ pub struct Mat33 {
 }
 
 impl Mat33 {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         m00: f32,
         m01: f32,
@@ -334,6 +356,7 @@ impl Mat33 {
         }
     }
 
+    #[allow(dead_code)]
     pub fn from_row_vec(v1: Vec3f, v2: Vec3f, v3: Vec3f) -> Mat33 {
         Mat33 {
             m: [[v1.x, v1.y, v1.z], [v2.x, v2.y, v2.z], [v3.x, v3.y, v3.z]],
@@ -353,6 +376,7 @@ impl Mat33 {
         )
     }
 
+    #[allow(dead_code)]
     pub fn determinant(&self) -> f32 {
         let m = &self.m;
         m[0][0] * m[1][1] * m[2][2] + m[0][1] * m[1][2] * m[2][0] + m[0][2] * m[1][0] * m[2][1]
@@ -361,6 +385,7 @@ impl Mat33 {
             - m[0][0] * m[1][2] * m[2][1]
     }
 
+    #[allow(dead_code)]
     pub fn cofactor(&self) -> Mat33 {
         let m = &self.m;
 
@@ -381,6 +406,7 @@ impl Mat33 {
         res
     }
 
+    #[allow(dead_code)]
     pub fn inverse(&self) -> Mat33 {
         self.cofactor().transposed() * (1.0 / self.determinant())
     }
@@ -422,6 +448,7 @@ pub struct Mat44 {
 }
 
 impl Mat44 {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         m00: f32,
         m01: f32,
@@ -484,6 +511,7 @@ impl Mat44 {
         m
     }
 
+    // Camera space looking down -z from the eye, with its origin at the center
     pub fn lookat(eye: Vec3f, center: Vec3f, up: Vec3f) -> Mat44 {
         let w = (eye - center).normalized();
         let u = up.cross(w).normalized();
@@ -494,19 +522,32 @@ impl Mat44 {
         res.m[0][0] = u.x;
         res.m[0][1] = u.y;
         res.m[0][2] = u.z;
-        res.m[0][3] = -center.x;
+        res.m[0][3] = -u.dot(center);
         res.m[1][0] = v.x;
         res.m[1][1] = v.y;
         res.m[1][2] = v.z;
-        res.m[1][3] = -center.y;
+        res.m[1][3] = -v.dot(center);
         res.m[2][0] = w.x;
         res.m[2][1] = w.y;
         res.m[2][2] = w.z;
-        res.m[2][3] = -center.z;
+        res.m[2][3] = -w.dot(center);
 
         res
     }
 
+    // Perspective projection of a camera looking down -z, with the vertical
+    // field of view in radians. Unlike OpenGL, the depth is reversed to match
+    // the z-buffer: the near plane is at 1 in NDC and the far plane at -1.
+    pub fn perspective(fov_y: f32, aspect: f32, near: f32, far: f32) -> Mat44 {
+        let f = 1.0 / (fov_y / 2.0).tan();
+        Mat44::new(
+            f / aspect, 0.0, 0.0, 0.0,
+            0.0, f, 0.0, 0.0,
+            0.0, 0.0, (far + near) / (far - near), 2.0 * far * near / (far - near),
+            0.0, 0.0, -1.0, 0.0,
+        )
+    }
+
     pub fn viewport(x: f32, y: f32, w: f32, h: f32, depth: f32) -> Mat44 {
         let mut m = Mat44::identity();
 
@@ -909,6 +950,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::excessive_precision)]
     fn mat44_inverse_test() {
         let m = Mat44::new(
             5.2, 5.8, 5.5, 7.7, 1.9, 4.5, 3.5, 6.3, 1.3, 6.1, 3.3, 4.4, 5.1, 4.6, 3.5, 1.1,
@@ -922,4 +964,27 @@ mod tests {
 
         assert_eq!(res, expected);
     }
+
+    #[test]
+    fn vec3f_add_assign_test() {
+        let mut v = Vec3f::new(1.0, 2.0, 3.0);
+        v += Vec3f::new(0.5, 0.25, 4.0);
+        assert_eq!(v, Vec3f::new(1.5, 2.25, 7.0));
+    }
+
+    #[test]
+    fn lookat_perspective_test() {
+        let (eye, center) = (Vec3f::new(1.0, 2.0, 3.0), Vec3f::new(2.0, 2.0, 3.0));
+        let m = Mat44::lookat(eye, center, Vec3f::new(0.0, 1.0, 0.0));
+        let origin = (m * Vec4f::from_vec3f(center, 1.0)).xyz();
+        assert!(origin.length() < 1e-6);
+        let eye = (m * Vec4f::from_vec3f(eye, 1.0)).xyz();
+        assert!((eye - Vec3f::new(0.0, 0.0, 1.0)).length() < 1e-6);
+
+        let p = Mat44::perspective(std::f32::consts::FRAC_PI_2, 2.0, 0.5, 10.0);
+        let near = (p * Vec4f::new(0.0, 0.5, -0.5, 1.0)).homogenize();
+        let far = (p * Vec4f::new(20.0, 0.0, -10.0, 1.0)).homogenize();
+        assert!((near - Vec3f::new(0.0, 1.0, 1.0)).length() < 1e-5);
+        assert!((far - Vec3f::new(1.0, 0.0, -1.0)).length() < 1e-5);
+    }
 }
@@ -0,0 +1,117 @@
+use color::BlendMode;
+use framebuffer::Framebuffer;
+use math::Vec4f;
+
+// Order independent transparency: every fragment is stored in a per pixel
+// linked list, then the lists are sorted and blended back to front.
+
+const END_OF_LIST: u32 = u32::MAX;
+
+#[derive(Debug, Clone, Copy)]
+struct Fragment {
+    // Linear color, with straight alpha
+    color: Vec4f,
+    depth: f32,
+    blend_mode: BlendMode,
+    next: u32,
+}
+
+pub struct FragmentLists {
+    heads: Framebuffer<u32>,
+    fragments: Vec<Fragment>,
+}
+
+impl FragmentLists {
+    pub fn new(width: usize, height: usize) -> FragmentLists {
+        FragmentLists {
+            heads: Framebuffer::new(width, height, END_OF_LIST),
+            fragments: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, x: usize, y: usize, color: Vec4f, depth: f32, blend_mode: BlendMode) {
+        let head = self.heads.get_mut(x, y);
+        self.fragments.push(Fragment {
+            color,
+            depth,
+            blend_mode,
+            next: *head,
+        });
+        *head = (self.fragments.len() - 1) as u32;
+    }
+
+    // Blends the fragments of each pixel back to front over all the samples
+    // of the color buffer, and empties the lists.
+    pub fn resolve(&mut self, color_buffer: &mut Framebuffer<Vec4f>) {
+        assert!(self.heads.width == color_buffer.width && self.heads.height == color_buffer.height);
+
+        let mut pixel_fragments = Vec::new();
+        for y in 0..self.heads.height {
+            for x in 0..self.heads.width {
+                let mut index = self.heads.get(x, y);
+                if index == END_OF_LIST {
+                    continue;
+                }
+
+                pixel_fragments.clear();
+                while index != END_OF_LIST {
+                    let fragment = self.fragments[index as usize];
+                    pixel_fragments.push(fragment);
+                    index = fragment.next;
+                }
+
+                // Greater depth is closer to the camera
+                pixel_fragments.sort_by(|a, b| a.depth.total_cmp(&b.depth));
+
+                for s in 0..color_buffer.samples {
+                    let dst = color_buffer.get_sample_mut(x, y, s);
+                    for fragment in &pixel_fragments {
+                        *dst = fragment.blend_mode.blend(fragment.color, *dst);
+                    }
+                }
+            }
+        }
+
+        self.heads.clear(END_OF_LIST);
+        self.fragments.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_order_independent_test() {
+        let fragments = [
+            (Vec4f::new(1.0, 0.0, 0.0, 0.5), 1.0),
+            (Vec4f::new(0.0, 1.0, 0.0, 0.5), 3.0),
+            (Vec4f::new(0.0, 0.0, 1.0, 0.5), 2.0),
+        ];
+
+        let resolve = |order: &[usize]| {
+            let mut lists = FragmentLists::new(1, 1);
+            let mut color_buffer = Framebuffer::new(1, 1, Vec4f::new(0.0, 0.0, 0.0, 1.0));
+            for &i in order {
+                lists.push(0, 0, fragments[i].0, fragments[i].1, BlendMode::Alpha);
+            }
+            lists.resolve(&mut color_buffer);
+            color_buffer.get(0, 0)
+        };
+
+        let a = resolve(&[0, 1, 2]);
+        let b = resolve(&[2, 0, 1]);
+        assert_eq!((a.x, a.y, a.z, a.w), (b.x, b.y, b.z, b.w));
+
+        // Green is the closest so it is blended last
+        assert_eq!((a.x, a.y, a.z), (0.125, 0.5, 0.25));
+
+        // A NaN depth, from a vertex at w = 0, doesn't stop the resolve
+        let mut lists = FragmentLists::new(1, 1);
+        let mut color_buffer = Framebuffer::new(1, 1, Vec4f::new(0.0, 0.0, 0.0, 1.0));
+        lists.push(0, 0, fragments[0].0, f32::NAN, BlendMode::Alpha);
+        lists.push(0, 0, fragments[1].0, 1.0, BlendMode::Alpha);
+        lists.resolve(&mut color_buffer);
+        assert_eq!(color_buffer.get(0, 0).w, 1.0);
+    }
+}
@@ -0,0 +1,194 @@
+use math::{Vec2f, Vec3f};
+use std::f32::consts::PI;
+
+// Metallic-roughness model: Cook-Torrance specular with the GGX distribution,
+// Smith geometry and Schlick Fresnel, over an energy-conserving Lambert
+// diffuse. Direct light radiance is the irradiance perpendicular to the
+// light, so a white Lambertian surface facing it reflects 1/pi of it.
+
+// Reflectance at normal incidence of the dielectrics
+pub const DIELECTRIC_F0: f32 = 0.04;
+
+// GGX degenerates for perfectly smooth surfaces
+const MIN_ROUGHNESS: f32 = 0.045;
+
+// Material parameters of the mesh, as in glTF: constant factors multiplied by
+// the maps, a missing map counts as white. Maps are file names in the resource
+// directory, the grayscale maps are read from their red channel.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MaterialSettings {
+    pub metallic: f32,
+    pub roughness: f32,
+    // Linear color
+    pub emissive: Vec3f,
+    pub metallic_map: Option<String>,
+    pub roughness_map: Option<String>,
+    pub occlusion_map: Option<String>,
+    // sRGB color
+    pub emissive_map: Option<String>,
+}
+
+impl Default for MaterialSettings {
+    fn default() -> MaterialSettings {
+        MaterialSettings {
+            metallic: 0.0,
+            roughness: 0.5,
+            emissive: Vec3f::new(0.0, 0.0, 0.0),
+            metallic_map: None,
+            roughness_map: None,
+            occlusion_map: None,
+            emissive_map: None,
+        }
+    }
+}
+
+// Material at a point of the surface
+#[derive(Debug, Clone, Copy)]
+pub struct Surface {
+    // Linear color
+    pub base_color: Vec3f,
+    pub metallic: f32,
+    // Perceptual roughness, squared for the GGX distribution
+    pub roughness: f32,
+    // Ambient occlusion, only applied to the ambient light
+    pub occlusion: f32,
+    pub emissive: Vec3f,
+}
+
+impl Surface {
+    // Metals have no diffuse and tint their reflection
+    pub fn f0(&self) -> Vec3f {
+        let dielectric = Vec3f::new(DIELECTRIC_F0, DIELECTRIC_F0, DIELECTRIC_F0);
+        dielectric * (1.0 - self.metallic) + self.base_color * self.metallic
+    }
+
+    pub fn diffuse_color(&self) -> Vec3f {
+        self.base_color * (1.0 - self.metallic)
+    }
+
+    fn alpha(&self) -> f32 {
+        let roughness = self.roughness.clamp(MIN_ROUGHNESS, 1.0);
+        roughness * roughness
+    }
+}
+
+// Normal distribution of the microfacets
+pub fn distribution_ggx(n_dot_h: f32, alpha: f32) -> f32 {
+    let a2 = alpha * alpha;
+    let d = n_dot_h * n_dot_h * (a2 - 1.0) + 1.0;
+    a2 / (PI * d * d)
+}
+
+fn geometry_schlick_smith(n_dot_v: f32, n_dot_l: f32, k: f32) -> f32 {
+    let schlick_ggx = |n_dot_x: f32| n_dot_x / (n_dot_x * (1.0 - k) + k);
+    schlick_ggx(n_dot_v) * schlick_ggx(n_dot_l)
+}
+
+// Shadowing and masking of the microfacets, Schlick-GGX for the light and
+// the view directions with the remapping of direct lights
+pub fn geometry_smith(n_dot_v: f32, n_dot_l: f32, roughness: f32) -> f32 {
+    geometry_schlick_smith(n_dot_v, n_dot_l, (roughness + 1.0) * (roughness + 1.0) / 8.0)
+}
+
+// Same with the remapping of image based lighting
+pub fn geometry_smith_ibl(n_dot_v: f32, n_dot_l: f32, roughness: f32) -> f32 {
+    geometry_schlick_smith(n_dot_v, n_dot_l, roughness * roughness / 2.0)
+}
+
+pub fn fresnel_schlick(cos_theta: f32, f0: Vec3f) -> Vec3f {
+    let f = (1.0 - cos_theta).clamp(0.0, 1.0).powi(5);
+    f0 * (1.0 - f) + Vec3f::new(f, f, f)
+}
+
+// Fresnel averaged over the rough reflections of the ambient light
+pub fn fresnel_schlick_roughness(cos_theta: f32, f0: Vec3f, roughness: f32) -> Vec3f {
+    let f = (1.0 - cos_theta).clamp(0.0, 1.0).powi(5);
+    let max = 1.0 - roughness;
+    let grazing = Vec3f::new(max.max(f0.x), max.max(f0.y), max.max(f0.z));
+    f0 + (grazing - f0) * f
+}
+
+// Radiance reflected towards `v` of the light coming from `l`. Directions
+// are normalized and point away from the surface.
+pub fn direct(surface: &Surface, n: Vec3f, v: Vec3f, l: Vec3f, radiance: Vec3f) -> Vec3f {
+    let n_dot_l = n.dot(l);
+    if n_dot_l <= 0.0 {
+        return Vec3f::default();
+    }
+    let n_dot_v = n.dot(v).max(1e-4);
+    let h = (v + l).normalized();
+
+    let fresnel = fresnel_schlick(h.dot(v).max(0.0), surface.f0());
+    let d = distribution_ggx(n.dot(h).max(0.0), surface.alpha());
+    let g = geometry_smith(n_dot_v, n_dot_l, surface.roughness.clamp(MIN_ROUGHNESS, 1.0));
+    let specular = fresnel * (d * g / (4.0 * n_dot_v * n_dot_l));
+
+    // What is not reflected by the specular is diffused
+    let diffuse = (Vec3f::new(1.0, 1.0, 1.0) - fresnel) * surface.diffuse_color() * (1.0 / PI);
+
+    (diffuse + specular) * radiance * n_dot_l
+}
+
+// Radiance reflected towards `v` of a uniform ambient light
+pub fn ambient(surface: &Surface, n: Vec3f, v: Vec3f, radiance: Vec3f) -> Vec3f {
+    let fresnel = fresnel_schlick_roughness(n.dot(v).max(0.0), surface.f0(), surface.roughness);
+    let diffuse = (Vec3f::new(1.0, 1.0, 1.0) - fresnel) * surface.diffuse_color();
+    (diffuse + fresnel) * radiance * surface.occlusion
+}
+
+// Radiance reflected towards `v` of an environment, with the split sum
+// approximation: `irradiance` is the ambient radiance lighting the diffuse,
+// `prefiltered` the radiance around the reflection blurred for the roughness
+// of the surface and `brdf` the scale and bias of f0 over the specular lobe.
+pub fn environment(surface: &Surface, n: Vec3f, v: Vec3f, irradiance: Vec3f, prefiltered: Vec3f, brdf: Vec2f) -> Vec3f {
+    let fresnel = fresnel_schlick_roughness(n.dot(v).max(0.0), surface.f0(), surface.roughness);
+    let diffuse = (Vec3f::new(1.0, 1.0, 1.0) - fresnel) * surface.diffuse_color() * irradiance;
+    let specular = prefiltered * (surface.f0() * brdf.x + Vec3f::new(brdf.y, brdf.y, brdf.y));
+    (diffuse + specular) * surface.occlusion
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn brdf_test() {
+        // The projected area of the microfacets is the area of the surface
+        for &alpha in [0.1, 0.5, 1.0].iter() {
+            let steps = 2000;
+            let mut integral = 0.0;
+            for i in 0..steps {
+                let theta = (i as f32 + 0.5) / steps as f32 * PI / 2.0;
+                let d_omega = 2.0 * PI * theta.sin() * (PI / 2.0 / steps as f32);
+                integral += distribution_ggx(theta.cos(), alpha) * theta.cos() * d_omega;
+            }
+            assert!((integral - 1.0).abs() < 0.02, "alpha {}: {}", alpha, integral);
+        }
+
+        let f0 = Vec3f::new(0.04, 0.5, 1.0);
+        assert!((fresnel_schlick(1.0, f0) - f0).length() < 1e-6);
+        assert!((fresnel_schlick(0.0, f0) - Vec3f::new(1.0, 1.0, 1.0)).length() < 1e-6);
+
+        let surface = Surface {
+            base_color: Vec3f::new(1.0, 1.0, 1.0),
+            metallic: 0.0,
+            roughness: 1.0,
+            occlusion: 1.0,
+            emissive: Vec3f::default(),
+        };
+        let n = Vec3f::new(0.0, 0.0, 1.0);
+        let white = Vec3f::new(1.0, 1.0, 1.0);
+
+        // Facing the light, a rough white dielectric reflects close to 1/pi
+        let lo = direct(&surface, n, n, n, white);
+        assert!(lo.x < 1.0 / PI && lo.x > 0.9 / PI, "{}", lo.x);
+        assert_eq!(direct(&surface, n, n, -n, white), Vec3f::default());
+
+        // Metals have no diffuse, only the grazing Fresnel is not tinted
+        let metal = Surface { metallic: 1.0, base_color: Vec3f::new(1.0, 0.0, 0.0), ..surface };
+        let l = Vec3f::new(0.6, 0.0, 0.8);
+        let v = Vec3f::new(-0.6, 0.0, 0.8);
+        let lo = direct(&metal, n, v, l, white);
+        assert!(lo.y == lo.z && lo.y < lo.x * 1e-3, "{:?}", lo);
+    }
+}
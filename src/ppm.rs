@@ -1,46 +1,48 @@
+use color;
+use resample::{self, Filter};
+use std::io::{Result, Write};
+
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Clone, Copy)]
 pub struct RGB {
     pub r: u8,
     pub g: u8,
     pub b: u8,
+    pub a: u8,
 }
 
 impl RGB {
     pub fn new(r: u8, g: u8, b: u8) -> RGB {
-        RGB { r, g, b }
+        RGB { r, g, b, a: 255 }
+    }
+
+    pub fn new_rgba(r: u8, g: u8, b: u8, a: u8) -> RGB {
+        RGB { r, g, b, a }
     }
 
     pub fn red() -> RGB {
-        RGB { r: 255, g: 0, b: 0 }
+        RGB::new(255, 0, 0)
     }
 
     pub fn green() -> RGB {
-        RGB { r: 0, g: 255, b: 0 }
+        RGB::new(0, 255, 0)
     }
 
     pub fn blue() -> RGB {
-        RGB { r: 0, g: 0, b: 255 }
+        RGB::new(0, 0, 255)
     }
 
     pub fn black() -> RGB {
-        RGB { r: 0, g: 0, b: 0 }
+        RGB::new(0, 0, 0)
     }
 
     pub fn white() -> RGB {
-        RGB {
-            r: 255,
-            g: 255,
-            b: 255,
-        }
+        RGB::new(255, 255, 255)
     }
 
     pub fn grey(v: f32) -> RGB {
         let v = (v * 255.0) as u8;
-        RGB {
-            r: v,
-            g: v,
-            b: v,
-        }
+        RGB::new(v, v, v)
     }
 }
 
@@ -53,14 +55,10 @@ pub struct Image {
 
 impl Image {
     pub fn new(width: usize, height: usize) -> Image {
-        let mut data = Vec::with_capacity(width * height);
-        for _ in 0..width * height {
-            data.push(RGB::black());
-        }
         Image {
             width,
             height,
-            data,
+            data: vec![RGB::black(); width * height],
         }
     }
 
@@ -69,9 +67,32 @@ impl Image {
     }
 
     pub fn get(&self, x: usize, y: usize) -> RGB {
-        println!("x: {}, y: {}", x, y);
         self.data[((self.height - 1) - y) * self.width + x]
     }
+
+    // Resizes the image, filtering happens on linear colors
+    pub fn resample(&self, width: usize, height: usize, filter: Filter) -> Image {
+        let linear: Vec<_> = self.data.iter().map(|&c| color::decode_srgba8(c)).collect();
+        let resampled = resample::resample(&linear, self.width, self.height, width, height, filter);
+        Image {
+            width,
+            height,
+            data: resampled.into_iter().map(color::encode_srgba8).collect(),
+        }
+    }
+}
+
+// Binary PAM (P7) with straight alpha, PPM can't store the alpha channel
+pub fn write_pam<W: Write>(w: &mut W, image: &Image) -> Result<()> {
+    write!(
+        w,
+        "P7\nWIDTH {}\nHEIGHT {}\nDEPTH 4\nMAXVAL 255\nTUPLTYPE RGB_ALPHA\nENDHDR\n",
+        image.width, image.height
+    )?;
+    for color in image.data.iter() {
+        w.write_all(&[color.r, color.g, color.b, color.a])?;
+    }
+    Ok(())
 }
 
 impl From<&Image> for String {
@@ -0,0 +1,547 @@
+use math::{Vec2f, Vec2i, Vec3f, Vec4f};
+
+// Rasterizer fixed function state
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CullMode {
+    None,
+    Back,
+    Front,
+}
+
+// Winding of the front faces, as seen on screen
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FrontFace {
+    Clockwise,
+    CounterClockwise,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RasterizerState {
+    pub cull_mode: CullMode,
+    pub front_face: FrontFace,
+    // Depth offset pushing the triangles away from the camera, in depth units,
+    // the slope scaled part grows with the depth slope of the triangle.
+    // Mostly used to avoid self shadowing in shadow maps.
+    pub depth_bias: f32,
+    pub slope_scaled_depth_bias: f32,
+}
+
+impl Default for RasterizerState {
+    fn default() -> RasterizerState {
+        RasterizerState {
+            cull_mode: CullMode::Back,
+            front_face: FrontFace::CounterClockwise,
+            depth_bias: 0.0,
+            slope_scaled_depth_bias: 0.0,
+        }
+    }
+}
+
+// Twice the signed area of the triangle, positive when counter clockwise
+// in screen space (y up)
+pub fn signed_area(a: Vec2f, b: Vec2f, c: Vec2f) -> f32 {
+    (b.x - a.x) * (c.y - a.y) - (c.x - a.x) * (b.y - a.y)
+}
+
+impl RasterizerState {
+    pub fn is_culled(&self, a: Vec2f, b: Vec2f, c: Vec2f) -> bool {
+        let area = signed_area(a, b, c);
+        let front_facing = match self.front_face {
+            FrontFace::CounterClockwise => area > 0.0,
+            FrontFace::Clockwise => area < 0.0,
+        };
+
+        match self.cull_mode {
+            CullMode::None => false,
+            CullMode::Back => !front_facing,
+            CullMode::Front => front_facing,
+        }
+    }
+
+    // Offset to subtract from the depth of the triangle, given its vertices
+    // in screen space. The depth slope is the largest of dz/dx and dz/dy.
+    pub fn depth_offset(&self, v: [Vec3f; 3]) -> f32 {
+        if self.slope_scaled_depth_bias == 0.0 {
+            return self.depth_bias;
+        }
+
+        let area = signed_area(v[0].xy(), v[1].xy(), v[2].xy());
+        if area == 0.0 {
+            return self.depth_bias;
+        }
+
+        let (e1, e2) = (v[1] - v[0], v[2] - v[0]);
+        let dzdx = (e1.z * e2.y - e2.z * e1.y) / area;
+        let dzdy = (e1.x * e2.z - e2.x * e1.z) / area;
+        self.depth_bias + self.slope_scaled_depth_bias * dzdx.abs().max(dzdy.abs())
+    }
+}
+
+// Smallest w of the clipped vertices, what is behind the eye has w <= 0
+pub const MIN_W: f32 = 1e-5;
+
+// Clips a triangle in homogeneous coordinates against the w = MIN_W plane.
+// The clipped polygon is split in triangles, returned with the barycentric
+// coordinates of their vertices in the original triangle.
+pub fn clip_triangle(v: [Vec4f; 3]) -> Vec<[(Vec4f, Vec3f); 3]> {
+    let bars = [Vec3f::new(1.0, 0.0, 0.0), Vec3f::new(0.0, 1.0, 0.0), Vec3f::new(0.0, 0.0, 1.0)];
+    if v.iter().all(|p| p.w >= MIN_W) {
+        return vec![[(v[0], bars[0]), (v[1], bars[1]), (v[2], bars[2])]];
+    }
+
+    let mut polygon = Vec::with_capacity(4);
+    for i in 0..3 {
+        let j = (i + 1) % 3;
+        let (da, db) = (v[i].w - MIN_W, v[j].w - MIN_W);
+        if da >= 0.0 {
+            polygon.push((v[i], bars[i]));
+        }
+        if (da >= 0.0) != (db >= 0.0) {
+            let t = da / (da - db);
+            polygon.push((v[i] * (1.0 - t) + v[j] * t, bars[i] * (1.0 - t) + bars[j] * t));
+        }
+    }
+
+    (1..polygon.len().saturating_sub(1))
+        .map(|i| [polygon[0], polygon[i], polygon[i + 1]])
+        .collect()
+}
+
+// Vertices are snapped to a fixed point grid, so that edge functions are
+// evaluated exactly and shared edges produce the same coverage.
+pub const SUBPIXEL_BITS: i32 = 8;
+const SUBPIXEL_ONE: i32 = 1 << SUBPIXEL_BITS;
+const SUBPIXEL_HALF: i32 = SUBPIXEL_ONE / 2;
+
+// Triangles are only clipped against the near plane, those reaching further
+// than this are dropped so that the fixed point coordinates can't overflow.
+const GUARD_BAND: f32 = (1 << 22) as f32;
+
+pub fn to_fixed(v: Vec2f) -> Vec2i {
+    let one = SUBPIXEL_ONE as f32;
+    Vec2i::new((v.x * one).round() as i32, (v.y * one).round() as i32)
+}
+
+// E(p) = a * p.x + b * p.y + c, positive on the left of v0 -> v1
+#[derive(Clone, Copy)]
+struct Edge {
+    a: i64,
+    b: i64,
+    c: i64,
+    bias: i64,
+}
+
+impl Edge {
+    fn new(v0: Vec2i, v1: Vec2i) -> Edge {
+        let a = i64::from(v0.y) - i64::from(v1.y);
+        let b = i64::from(v1.x) - i64::from(v0.x);
+        let c = -(a * i64::from(v0.x) + b * i64::from(v0.y));
+
+        // Top-left fill rule: samples exactly on an edge belong to the triangle
+        // only for its top and left edges. With y up and counter clockwise
+        // winding, left edges go down and top edges go left.
+        let top_left = a > 0 || (a == 0 && b < 0);
+
+        Edge {
+            a,
+            b,
+            c,
+            bias: if top_left { 0 } else { -1 },
+        }
+    }
+
+    #[inline(always)]
+    fn eval(&self, p: Vec2i) -> i64 {
+        self.a * i64::from(p.x) + self.b * i64::from(p.y) + self.c
+    }
+}
+
+// Standard multisample patterns, in 1/16th of pixel from the pixel center.
+// Same positions as Direct3D, with y flipped.
+const PATTERN_1X: [(i32, i32); 1] = [(0, 0)];
+const PATTERN_2X: [(i32, i32); 2] = [(4, -4), (-4, 4)];
+const PATTERN_4X: [(i32, i32); 4] = [(-2, 6), (6, 2), (-6, -2), (2, -6)];
+const PATTERN_8X: [(i32, i32); 8] = [
+    (1, 3),
+    (-1, -3),
+    (5, -1),
+    (-3, 5),
+    (-5, -5),
+    (-7, 1),
+    (3, -7),
+    (7, 7),
+];
+
+pub const MAX_SAMPLES: usize = 8;
+
+pub fn sample_pattern(samples: usize) -> &'static [(i32, i32)] {
+    match samples {
+        1 => &PATTERN_1X,
+        2 => &PATTERN_2X,
+        4 => &PATTERN_4X,
+        8 => &PATTERN_8X,
+        _ => panic!("Unsupported sample count {}", samples),
+    }
+}
+
+// Coverage of one pixel by a triangle
+pub struct Coverage {
+    // Bit `s` is set when sample `s` is covered
+    pub mask: u32,
+    // Barycentric coordinates of each sample, to interpolate per sample values
+    pub sample_bars: [Vec3f; MAX_SAMPLES],
+    // Barycentric coordinates to shade the pixel with: at the pixel center when
+    // it is covered, at the first covered sample otherwise
+    pub bar: Vec3f,
+}
+
+// Calls `fragment` with the pixel coordinates and the coverage of every
+// pixel with at least one sample covered by the triangle, whatever its winding.
+pub fn rasterize_triangle<F>(v: [Vec2f; 3], width: usize, height: usize, samples: usize, mut fragment: F)
+where
+    F: FnMut(usize, usize, &Coverage),
+{
+    for p in v.iter() {
+        // Also rejects NaNs
+        if !(p.x.abs() < GUARD_BAND && p.y.abs() < GUARD_BAND) {
+            return;
+        }
+    }
+
+    let mut p = [to_fixed(v[0]), to_fixed(v[1]), to_fixed(v[2])];
+    let mut area = Edge::new(p[0], p[1]).eval(p[2]);
+    if area == 0 {
+        return;
+    }
+
+    // Edge functions expect counter clockwise triangles
+    let flipped = area < 0;
+    if flipped {
+        p.swap(1, 2);
+        area = -area;
+    }
+
+    let xmin = p[0].x.min(p[1].x.min(p[2].x));
+    let ymin = p[0].y.min(p[1].y.min(p[2].y));
+    let xmax = p[0].x.max(p[1].x.max(p[2].x));
+    let ymax = p[0].y.max(p[1].y.max(p[2].y));
+
+    // Pixel centers are at half integer coordinates, samples are less than
+    // half a pixel away from the center
+    let xmin = ((xmin - SUBPIXEL_ONE) >> SUBPIXEL_BITS).max(0);
+    let ymin = ((ymin - SUBPIXEL_ONE) >> SUBPIXEL_BITS).max(0);
+    let xmax = (xmax >> SUBPIXEL_BITS).min(width as i32 - 1);
+    let ymax = (ymax >> SUBPIXEL_BITS).min(height as i32 - 1);
+
+    if xmin > xmax || ymin > ymax {
+        return;
+    }
+
+    // Each edge function gives the weight of the opposite vertex
+    let edges = [Edge::new(p[1], p[2]), Edge::new(p[2], p[0]), Edge::new(p[0], p[1])];
+
+    // Edge function offsets from the pixel center to each sample
+    let pattern = sample_pattern(samples);
+    let mut sample_offsets = [[0_i64; 3]; MAX_SAMPLES];
+    for (offsets, &(sx, sy)) in sample_offsets.iter_mut().zip(pattern.iter()) {
+        let (sx, sy) = (i64::from(sx * SUBPIXEL_ONE / 16), i64::from(sy * SUBPIXEL_ONE / 16));
+        for (offset, edge) in offsets.iter_mut().zip(edges.iter()) {
+            *offset = edge.a * sx + edge.b * sy;
+        }
+    }
+
+    let origin = Vec2i::new(
+        (xmin << SUBPIXEL_BITS) + SUBPIXEL_HALF,
+        (ymin << SUBPIXEL_BITS) + SUBPIXEL_HALF,
+    );
+    let mut row = [edges[0].eval(origin), edges[1].eval(origin), edges[2].eval(origin)];
+
+    let one = i64::from(SUBPIXEL_ONE);
+    let inv_area = 1.0 / area as f32;
+    let inside = |w: &[i64; 3]| {
+        w[0] + edges[0].bias >= 0 && w[1] + edges[1].bias >= 0 && w[2] + edges[2].bias >= 0
+    };
+    let barycentric = |w: &[i64; 3]| {
+        let bar = Vec3f::new(w[0] as f32 * inv_area, w[1] as f32 * inv_area, w[2] as f32 * inv_area);
+        if flipped { Vec3f::new(bar.x, bar.z, bar.y) } else { bar }
+    };
+
+    let mut coverage = Coverage {
+        mask: 0,
+        sample_bars: [Vec3f::default(); MAX_SAMPLES],
+        bar: Vec3f::default(),
+    };
+
+    for y in ymin..=ymax {
+        let mut w = row;
+        for x in xmin..=xmax {
+            coverage.mask = 0;
+            for (s, offsets) in sample_offsets.iter().enumerate().take(pattern.len()) {
+                let ws = [w[0] + offsets[0], w[1] + offsets[1], w[2] + offsets[2]];
+                if inside(&ws) {
+                    if coverage.mask == 0 {
+                        coverage.bar = barycentric(&ws);
+                    }
+                    coverage.mask |= 1 << s;
+                    coverage.sample_bars[s] = barycentric(&ws);
+                }
+            }
+
+            if coverage.mask != 0 {
+                if inside(&w) {
+                    coverage.bar = barycentric(&w);
+                }
+                fragment(x as usize, y as usize, &coverage);
+            }
+
+            for i in 0..3 {
+                w[i] += edges[i].a * one;
+            }
+        }
+
+        for i in 0..3 {
+            row[i] += edges[i].b * one;
+        }
+    }
+}
+
+// Calls `fragment` for every pixel with at least one sample closer than half
+// `line_width` to the segment. Barycentric coordinates are (1 - t, t, 0) with
+// t the position of the closest point along the segment, so that vertex
+// values are interpolated the same way as for triangles.
+pub fn rasterize_line<F>(v: [Vec2f; 2], line_width: f32, width: usize, height: usize, samples: usize, mut fragment: F)
+where
+    F: FnMut(usize, usize, &Coverage),
+{
+    for p in v.iter() {
+        if !(p.x.abs() < GUARD_BAND && p.y.abs() < GUARD_BAND) {
+            return;
+        }
+    }
+
+    let half_width = line_width * 0.5;
+    let xmin = ((v[0].x.min(v[1].x) - half_width - 1.0).floor() as i32).max(0);
+    let ymin = ((v[0].y.min(v[1].y) - half_width - 1.0).floor() as i32).max(0);
+    let xmax = ((v[0].x.max(v[1].x) + half_width).floor() as i32).min(width as i32 - 1);
+    let ymax = ((v[0].y.max(v[1].y) + half_width).floor() as i32).min(height as i32 - 1);
+
+    let d = v[1] - v[0];
+    let length2 = d.x * d.x + d.y * d.y;
+
+    // Closest point of the segment to p, and whether it is inside the line
+    let closest = |p: Vec2f| {
+        let t = if length2 > 0.0 {
+            (((p.x - v[0].x) * d.x + (p.y - v[0].y) * d.y) / length2).clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+        let (dx, dy) = (v[0].x + d.x * t - p.x, v[0].y + d.y * t - p.y);
+        (t, dx * dx + dy * dy <= half_width * half_width)
+    };
+
+    let pattern = sample_pattern(samples);
+    let mut coverage = Coverage {
+        mask: 0,
+        sample_bars: [Vec3f::default(); MAX_SAMPLES],
+        bar: Vec3f::default(),
+    };
+
+    for y in ymin..=ymax {
+        for x in xmin..=xmax {
+            let center = Vec2f::new(x as f32 + 0.5, y as f32 + 0.5);
+
+            coverage.mask = 0;
+            for (s, &(sx, sy)) in pattern.iter().enumerate() {
+                let (t, inside) = closest(center + Vec2f::new(sx as f32 / 16.0, sy as f32 / 16.0));
+                if inside {
+                    if coverage.mask == 0 {
+                        coverage.bar = Vec3f::new(1.0 - t, t, 0.0);
+                    }
+                    coverage.mask |= 1 << s;
+                    coverage.sample_bars[s] = Vec3f::new(1.0 - t, t, 0.0);
+                }
+            }
+
+            if coverage.mask != 0 {
+                let (t, inside) = closest(center);
+                if inside {
+                    coverage.bar = Vec3f::new(1.0 - t, t, 0.0);
+                }
+                fragment(x as usize, y as usize, &coverage);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn coverage(triangles: &[[Vec2f; 3]], size: usize) -> Vec<u32> {
+        let mut res = vec![0; size * size];
+        for &t in triangles {
+            rasterize_triangle(t, size, size, 1, |x, y, _| res[y * size + x] += 1);
+        }
+        res
+    }
+
+    #[test]
+    fn shared_edges_cover_once_test() {
+        // Edges go exactly through pixel centers
+        let (a, b, c, d) = (
+            Vec2f::new(0.5, 0.5),
+            Vec2f::new(3.5, 0.5),
+            Vec2f::new(3.5, 3.5),
+            Vec2f::new(0.5, 3.5),
+        );
+
+        for triangles in [[[a, b, c], [a, c, d]], [[a, c, b], [a, d, c]]].iter() {
+            let res = coverage(triangles, 4);
+            for y in 0..4 {
+                for x in 0..4 {
+                    // Left and top edges are inclusive, right and bottom are not
+                    let expected = if x < 3 && y > 0 { 1 } else { 0 };
+                    assert_eq!(res[y * 4 + x], expected, "pixel {} {}", x, y);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn fan_covers_once_test() {
+        let center = Vec2f::new(8.0, 8.0);
+        let mut triangles = Vec::new();
+        for i in 0..7 {
+            let angle = |i: i32| i as f32 * std::f32::consts::PI * 2.0 / 7.0;
+            let p0 = Vec2f::new(8.0 + 7.3 * angle(i).cos(), 8.0 + 7.3 * angle(i).sin());
+            let p1 = Vec2f::new(8.0 + 7.3 * angle(i + 1).cos(), 8.0 + 7.3 * angle(i + 1).sin());
+            triangles.push([center, p0, p1]);
+        }
+
+        let res = coverage(&triangles, 16);
+        assert!(res.iter().all(|&c| c <= 1));
+        assert_eq!(res[8 * 16 + 8], 1);
+    }
+
+    #[test]
+    fn thin_triangle_test() {
+        let t = [Vec2f::new(1.4, 1.4), Vec2f::new(1.6, 1.4), Vec2f::new(1.5, 1.6)];
+        let res = coverage(&[t], 4);
+        assert_eq!(res.iter().sum::<u32>(), 1);
+        assert_eq!(res[4 + 1], 1);
+
+        let degenerate = [Vec2f::new(0.0, 0.0), Vec2f::new(1.0, 1.0), Vec2f::new(2.0, 2.0)];
+        assert_eq!(coverage(&[degenerate], 4).iter().sum::<u32>(), 0);
+    }
+
+    #[test]
+    fn barycentric_test() {
+        let t = [Vec2f::new(0.5, 0.5), Vec2f::new(8.5, 0.5), Vec2f::new(0.5, 8.5)];
+        let mut found = false;
+        rasterize_triangle(t, 10, 10, 1, |x, y, coverage| {
+            let bar = coverage.bar;
+            assert!((bar.x + bar.y + bar.z - 1.0).abs() < 0.0001);
+            if x == 4 && y == 2 {
+                assert!((bar.y - 0.5).abs() < 0.0001 && (bar.z - 0.25).abs() < 0.0001);
+                found = true;
+            }
+        });
+        assert!(found);
+    }
+
+    #[test]
+    fn multisample_coverage_test() {
+        // Left half of the pixel is covered, right edge going through its center
+        let t = [Vec2f::new(-1.0, -1.0), Vec2f::new(0.5, -1.0), Vec2f::new(0.5, 3.0)];
+        for &samples in [2, 4, 8].iter() {
+            let mut mask = 0;
+            rasterize_triangle(t, 1, 1, samples, |_, _, coverage| mask = coverage.mask);
+            assert_eq!(mask.count_ones() as usize, samples / 2, "{} samples", samples);
+        }
+
+        // Fully covered pixel
+        let t = [Vec2f::new(-1.0, -1.0), Vec2f::new(3.0, -1.0), Vec2f::new(-1.0, 3.0)];
+        rasterize_triangle(t, 1, 1, 8, |_, _, coverage| assert_eq!(coverage.mask, 0xff));
+    }
+
+    #[test]
+    fn depth_offset_test() {
+        let state = RasterizerState {
+            depth_bias: 1.0,
+            slope_scaled_depth_bias: 2.0,
+            ..RasterizerState::default()
+        };
+
+        // Depth grows by 3 per pixel along x, and 0.5 along y
+        let v = [Vec3f::new(0.0, 0.0, 10.0), Vec3f::new(4.0, 0.0, 22.0), Vec3f::new(0.0, 4.0, 12.0)];
+        assert_eq!(state.depth_offset(v), 7.0);
+        assert_eq!(state.depth_offset([v[0], v[2], v[1]]), 7.0);
+
+        let flat = [Vec3f::new(0.0, 0.0, 10.0), Vec3f::new(4.0, 0.0, 10.0), Vec3f::new(0.0, 4.0, 10.0)];
+        assert_eq!(state.depth_offset(flat), 1.0);
+    }
+
+    #[test]
+    fn culling_test() {
+        let (a, b, c) = (Vec2f::new(0.0, 0.0), Vec2f::new(4.0, 0.0), Vec2f::new(0.0, 4.0));
+        let cases = [
+            // Cull mode, front face, counter clockwise culled, clockwise culled
+            (CullMode::None, FrontFace::CounterClockwise, false, false),
+            (CullMode::None, FrontFace::Clockwise, false, false),
+            (CullMode::Back, FrontFace::CounterClockwise, false, true),
+            (CullMode::Back, FrontFace::Clockwise, true, false),
+            (CullMode::Front, FrontFace::CounterClockwise, true, false),
+            (CullMode::Front, FrontFace::Clockwise, false, true),
+        ];
+        for &(cull_mode, front_face, ccw_culled, cw_culled) in cases.iter() {
+            let state = RasterizerState { cull_mode, front_face, ..RasterizerState::default() };
+            assert_eq!(state.is_culled(a, b, c), ccw_culled, "{:?} {:?} counter clockwise", cull_mode, front_face);
+            assert_eq!(state.is_culled(a, c, b), cw_culled, "{:?} {:?} clockwise", cull_mode, front_face);
+        }
+    }
+
+    #[test]
+    fn line_test() {
+        // Horizontal line through the centers of the second row
+        let mut res = [0; 8 * 4];
+        rasterize_line([Vec2f::new(1.5, 1.5), Vec2f::new(5.5, 1.5)], 1.0, 8, 4, 1, |x, y, coverage| {
+            assert!((coverage.bar.y - (x as f32 - 1.0) / 4.0).abs() < 0.0001);
+            res[y * 8 + x] += 1;
+        });
+        for y in 0..4 {
+            for x in 0..8 {
+                let expected = if y == 1 && (1..=5).contains(&x) { 1 } else { 0 };
+                assert_eq!(res[y * 8 + x], expected, "pixel {} {}", x, y);
+            }
+        }
+
+        // Thick lines cover the neighbouring rows, and are clipped to the target
+        let mut count = 0;
+        rasterize_line([Vec2f::new(-10.0, 1.5), Vec2f::new(20.0, 1.5)], 3.0, 8, 4, 1, |_, _, _| count += 1);
+        assert_eq!(count, 8 * 3);
+    }
+
+    #[test]
+    fn clip_triangle_test() {
+        let inside = [Vec4f::new(0.0, 0.0, 0.0, 1.0), Vec4f::new(1.0, 0.0, 0.0, 1.0), Vec4f::new(0.0, 1.0, 0.0, 2.0)];
+        assert_eq!(clip_triangle(inside).len(), 1);
+
+        let behind = [Vec4f::new(0.0, 0.0, 0.0, -1.0), Vec4f::new(1.0, 0.0, 0.0, -1.0), Vec4f::new(0.0, 1.0, 0.0, 0.0)];
+        assert!(clip_triangle(behind).is_empty());
+
+        // One vertex behind the eye, the quad left is split in two triangles
+        let v = [Vec4f::new(0.0, 0.0, 0.0, 1.0), Vec4f::new(1.0, 0.0, 0.0, 1.0), Vec4f::new(0.0, 1.0, 0.0, -1.0)];
+        let triangles = clip_triangle(v);
+        assert_eq!(triangles.len(), 2);
+        for t in triangles.iter() {
+            for &(p, bar) in t.iter() {
+                assert!(p.w >= MIN_W * 0.999);
+                // The barycentric coordinates give back the clipped vertex
+                let q = v[0] * bar.x + v[1] * bar.y + v[2] * bar.z;
+                assert!((q - p).length() < 1e-5);
+                assert!((bar.x + bar.y + bar.z - 1.0).abs() < 1e-5);
+            }
+        }
+    }
+}
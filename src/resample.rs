@@ -0,0 +1,159 @@
+use math::Vec4f;
+
+// Separable image resampling with reconstruction filters. Works on linear
+// colors with premultiplied alpha, see `ppm::Image::resample`.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Filter {
+    Box,
+    Tent,
+    // Mitchell-Netravali cubic, with B = C = 1/3
+    Mitchell,
+    // Lanczos windowed sinc, with 3 lobes
+    Lanczos,
+}
+
+impl Filter {
+    // Radius of the filter, in source pixels when not scaling
+    fn support(self) -> f32 {
+        match self {
+            Filter::Box => 0.5,
+            Filter::Tent => 1.0,
+            Filter::Mitchell => 2.0,
+            Filter::Lanczos => 3.0,
+        }
+    }
+
+    fn weight(self, x: f32) -> f32 {
+        let x = x.abs();
+        match self {
+            Filter::Box => {
+                if x <= 0.5 { 1.0 } else { 0.0 }
+            }
+            Filter::Tent => (1.0 - x).max(0.0),
+            Filter::Mitchell => {
+                const B: f32 = 1.0 / 3.0;
+                const C: f32 = 1.0 / 3.0;
+                if x < 1.0 {
+                    ((12.0 - 9.0 * B - 6.0 * C) * x * x * x
+                        + (-18.0 + 12.0 * B + 6.0 * C) * x * x
+                        + (6.0 - 2.0 * B))
+                        / 6.0
+                } else if x < 2.0 {
+                    ((-B - 6.0 * C) * x * x * x
+                        + (6.0 * B + 30.0 * C) * x * x
+                        + (-12.0 * B - 48.0 * C) * x
+                        + (8.0 * B + 24.0 * C))
+                        / 6.0
+                } else {
+                    0.0
+                }
+            }
+            Filter::Lanczos => {
+                let sinc = |x: f32| {
+                    if x < 1e-5 {
+                        1.0
+                    } else {
+                        let px = std::f32::consts::PI * x;
+                        px.sin() / px
+                    }
+                };
+                if x < 3.0 { sinc(x) * sinc(x / 3.0) } else { 0.0 }
+            }
+        }
+    }
+}
+
+// Source samples contributing to one destination sample
+struct Contribution {
+    first: usize,
+    weights: Vec<f32>,
+}
+
+fn contributions(src_len: usize, dst_len: usize, filter: Filter) -> Vec<Contribution> {
+    let scale = src_len as f32 / dst_len as f32;
+    // When minifying, the filter is stretched to cover all the source samples
+    let filter_scale = scale.max(1.0);
+    let support = filter.support() * filter_scale;
+
+    (0..dst_len)
+        .map(|i| {
+            let center = (i as f32 + 0.5) * scale;
+            let first = (center - support).floor().max(0.0) as usize;
+            let last = ((center + support).ceil() as usize).min(src_len);
+
+            let mut weights: Vec<f32> = (first..last)
+                .map(|j| filter.weight((j as f32 + 0.5 - center) / filter_scale))
+                .collect();
+
+            let sum: f32 = weights.iter().sum();
+            if sum.abs() > 1e-6 {
+                for w in weights.iter_mut() {
+                    *w /= sum;
+                }
+            }
+
+            Contribution { first, weights }
+        })
+        .collect()
+}
+
+// Resamples every row of the image to `dst_width`, and transposes the result
+fn resample_rows_transposed(src: &[Vec4f], width: usize, height: usize, dst_width: usize, filter: Filter) -> Vec<Vec4f> {
+    let contribs = contributions(width, dst_width, filter);
+    let mut dst = vec![Vec4f::default(); dst_width * height];
+
+    for y in 0..height {
+        let row = &src[y * width..(y + 1) * width];
+        for (x, contrib) in contribs.iter().enumerate() {
+            let mut acc = Vec4f::default();
+            for (k, &w) in contrib.weights.iter().enumerate() {
+                acc = acc + row[contrib.first + k] * w;
+            }
+            dst[x * height + y] = acc;
+        }
+    }
+
+    dst
+}
+
+// Pixels are stored row by row
+pub fn resample(src: &[Vec4f], width: usize, height: usize, dst_width: usize, dst_height: usize, filter: Filter) -> Vec<Vec4f> {
+    assert_eq!(src.len(), width * height);
+    let tmp = resample_rows_transposed(src, width, height, dst_width, filter);
+    resample_rows_transposed(&tmp, height, dst_width, dst_height, filter)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILTERS: [Filter; 4] = [Filter::Box, Filter::Tent, Filter::Mitchell, Filter::Lanczos];
+
+    #[test]
+    fn constant_image_test() {
+        let c = Vec4f::new(0.25, 0.5, 0.75, 1.0);
+        let src = vec![c; 12 * 8];
+        for &filter in FILTERS.iter() {
+            for &(w, h) in [(3, 2), (12, 8), (30, 17)].iter() {
+                let dst = resample(&src, 12, 8, w, h, filter);
+                assert_eq!(dst.len(), w * h);
+                for p in dst {
+                    assert!((p.x - c.x).abs() < 0.001 && (p.z - c.z).abs() < 0.001, "{:?}", filter);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn box_downsample_test() {
+        let src = vec![
+            Vec4f::new(1.0, 0.0, 0.0, 1.0),
+            Vec4f::new(0.0, 0.0, 0.0, 1.0),
+            Vec4f::new(0.0, 1.0, 0.0, 1.0),
+            Vec4f::new(0.0, 0.0, 0.0, 0.0),
+        ];
+        let dst = resample(&src, 2, 2, 1, 1, Filter::Box);
+        assert_eq!((dst[0].x, dst[0].y, dst[0].z, dst[0].w), (0.25, 0.25, 0.0, 0.75));
+    }
+}
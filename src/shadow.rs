@@ -0,0 +1,902 @@
+use framebuffer::Framebuffer;
+use light::light_up;
+use math::{Mat44, Vec2f, Vec3f, Vec4f};
+use std::ops::{Add, Mul};
+
+// Depth of the scene as seen from a light, rendered in a pass of its own.
+// Depth is greater closer to the light, like the z-buffer.
+
+// How the shadow map is sampled, from hard to soft shadows
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ShadowFilter {
+    // Single depth comparison
+    Hard,
+    // Percentage closer filtering, averages the comparisons of a square
+    // kernel of texels
+    Pcf { kernel: usize },
+    // Percentage closer filtering on a rotated Poisson disk, radius in texels
+    Poisson { radius: f32 },
+    // Percentage closer soft shadows, the penumbra grows with the distance
+    // between blockers and receivers. The light size is the tangent of the
+    // angular radius of the light.
+    Pcss { light_size: f32 },
+    // Variance shadow map: the mean and variance of the blurred depths bound
+    // the lit fraction (Chebyshev inequality). Light bleeding reduction, in
+    // [0, 1), cuts the lowest visibilities off.
+    Variance { blur_radius: usize, light_bleeding: f32 },
+    // Exponential shadow map, from the blurred exp(-c * depth) with the
+    // exponent c in 1 / world units
+    Exponential { blur_radius: usize, exponent: f32 },
+}
+
+impl ShadowFilter {
+    // PCSS and the prefiltered filters measure distances in world units, only
+    // the orthographic shadow maps of the directional lights keep them
+    pub fn needs_orthographic_map(&self) -> bool {
+        matches!(self, ShadowFilter::Pcss { .. } | ShadowFilter::Variance { .. } | ShadowFilter::Exponential { .. })
+    }
+}
+
+// Parameters of the filters, shared by the filters of every light
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ShadowFilterParameters {
+    pub pcf_kernel: usize,
+    pub poisson_radius: f32,
+    pub light_size: f32,
+    pub blur_radius: usize,
+    pub light_bleeding: f32,
+    pub esm_exponent: f32,
+}
+
+impl Default for ShadowFilterParameters {
+    fn default() -> ShadowFilterParameters {
+        ShadowFilterParameters {
+            pcf_kernel: 3,
+            poisson_radius: 2.0,
+            light_size: 0.05,
+            blur_radius: 2,
+            light_bleeding: 0.3,
+            esm_exponent: 40.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ShadowSettings {
+    // Width and height of the shadow map, in texels
+    pub resolution: usize,
+    // Rasterizer depth bias of the shadow pass, see `RasterizerState`
+    pub depth_bias: f32,
+    pub slope_scaled_depth_bias: f32,
+    // Filter of the lights without a filter of their own
+    pub filter: ShadowFilter,
+    pub filter_parameters: ShadowFilterParameters,
+    // Number of slices of the view frustum, each with its own shadow map
+    pub cascades: usize,
+    // Split scheme of the cascades, from uniform (0) to logarithmic (1)
+    pub split_lambda: f32,
+    // Fraction of each cascade blended with the next one
+    pub cascade_blend: f32,
+}
+
+impl Default for ShadowSettings {
+    fn default() -> ShadowSettings {
+        ShadowSettings {
+            resolution: 1024,
+            depth_bias: 2.0,
+            slope_scaled_depth_bias: 1.5,
+            filter: ShadowFilter::Hard,
+            filter_parameters: ShadowFilterParameters::default(),
+            cascades: 1,
+            split_lambda: 0.75,
+            cascade_blend: 0.1,
+        }
+    }
+}
+
+// Poisson disk with 16 samples in the unit circle
+const POISSON_DISK: [(f32, f32); 16] = [
+    (-0.942_016_24, -0.399_062_16),
+    (0.945_586_1, -0.768_907_25),
+    (-0.094_184_1, -0.929_388_7),
+    (0.344_959_38, 0.293_877_6),
+    (-0.915_885_8, 0.457_714_32),
+    (-0.815_442_3, -0.879_124_64),
+    (-0.382_775_43, 0.276_768_45),
+    (0.974_844, 0.756_483_8),
+    (0.443_233_25, -0.975_115_5),
+    (0.537_429_8, -0.473_734_2),
+    (-0.264_969_1, -0.418_930_23),
+    (0.791_975_14, 0.190_901_88),
+    (-0.241_888_4, 0.997_065_07),
+    (-0.814_099_55, 0.914_375_9),
+    (0.199_841_26, 0.786_413_67),
+    (0.143_831_61, -0.141_007_9),
+];
+
+// Largest penumbra of the soft shadows, in texels
+const MAX_PENUMBRA: f32 = 32.0;
+
+// Lower bound of the variance, hides the numerical errors of flat receivers
+const MIN_VARIANCE: f32 = 1e-6;
+
+// Filterable shadow maps computed from the depth, distances in world units
+// from the farthest rendered depth
+enum Prefiltered {
+    None,
+    // Blurred depth and squared depth
+    Moments(Framebuffer<Vec2f>),
+    // Blurred exp(-c * depth)
+    Exponential(Framebuffer<f32>),
+}
+
+// Separable gaussian blur, clamping at the borders
+fn blur<T: Copy + Add<Output = T> + Mul<f32, Output = T>>(src: &Framebuffer<T>, radius: usize) -> Framebuffer<T> {
+    if radius == 0 {
+        return src.clone();
+    }
+
+    let sigma = (radius as f32 + 1.0) / 2.0;
+    let weights: Vec<f32> = (0..=2 * radius)
+        .map(|i| {
+            let x = i as f32 - radius as f32;
+            (-x * x / (2.0 * sigma * sigma)).exp()
+        })
+        .collect();
+    let total: f32 = weights.iter().sum();
+
+    let pass = |src: &Framebuffer<T>, dx: usize, dy: usize| {
+        let mut dst = src.clone();
+        let (w, h) = (src.width as isize, src.height as isize);
+        for y in 0..h {
+            for x in 0..w {
+                let mut acc = src.get(x as usize, y as usize) * 0.0;
+                for (i, &weight) in weights.iter().enumerate() {
+                    let offset = i as isize - radius as isize;
+                    let sx = (x + offset * dx as isize).clamp(0, w - 1);
+                    let sy = (y + offset * dy as isize).clamp(0, h - 1);
+                    acc = acc + src.get(sx as usize, sy as usize) * (weight / total);
+                }
+                *dst.get_mut(x as usize, y as usize) = acc;
+            }
+        }
+        dst
+    };
+
+    pass(&pass(src, 1, 0), 0, 1)
+}
+
+// Bilinear interpolation between the texel centers, None outside of the map
+fn sample_bilinear<T: Copy + Add<Output = T> + Mul<f32, Output = T>>(map: &Framebuffer<T>, pos: Vec2f) -> Option<T> {
+    if !(pos.x >= 0.0 && pos.y >= 0.0 && pos.x < map.width as f32 && pos.y < map.height as f32) {
+        return None;
+    }
+
+    let (x, y) = (pos.x - 0.5, pos.y - 0.5);
+    let (fx, fy) = (x - x.floor(), y - y.floor());
+    let clamp_x = |x: f32| (x.max(0.0) as usize).min(map.width - 1);
+    let clamp_y = |y: f32| (y.max(0.0) as usize).min(map.height - 1);
+    let (x0, x1) = (clamp_x(x.floor()), clamp_x(x.floor() + 1.0));
+    let (y0, y1) = (clamp_y(y.floor()), clamp_y(y.floor() + 1.0));
+
+    let bottom = map.get(x0, y0) * (1.0 - fx) + map.get(x1, y0) * fx;
+    let top = map.get(x0, y1) * (1.0 - fx) + map.get(x1, y1) * fx;
+    Some(bottom * (1.0 - fy) + top * fy)
+}
+
+pub struct ShadowMap {
+    pub depth: Framebuffer<f32>,
+    // World space to shadow map space: x and y in texels, z in depth units
+    pub light_from_world: Mat44,
+    pub filter: ShadowFilter,
+    // Scales of the orthographic projection, to convert the shadow map
+    // distances back to world units. Perspective maps have no such scales.
+    orthographic: bool,
+    texels_per_unit: f32,
+    depth_per_unit: f32,
+    prefiltered: Prefiltered,
+    // Filter of the last `prefilter`, the maps go stale when the filter changes
+    prefiltered_filter: Option<ShadowFilter>,
+    // Farthest rendered depth, origin of the prefiltered depths
+    reference_depth: f32,
+}
+
+impl ShadowMap {
+    pub fn new(resolution: usize, light_from_world: Mat44) -> ShadowMap {
+        let row_length = |r: [f32; 4]| Vec3f::new(r[0], r[1], r[2]).length();
+        ShadowMap {
+            depth: Framebuffer::new(resolution, resolution, f32::MIN),
+            light_from_world,
+            filter: ShadowFilter::Hard,
+            orthographic: light_from_world.m[3] == [0.0, 0.0, 0.0, 1.0],
+            texels_per_unit: row_length(light_from_world.m[0]),
+            depth_per_unit: row_length(light_from_world.m[2]),
+            prefiltered: Prefiltered::None,
+            prefiltered_filter: None,
+            reference_depth: 0.0,
+        }
+    }
+
+    // Perspective shadow map, given the projection of the light to clip space
+    // with the depth reversed, see `Mat44::perspective`
+    pub fn perspective(resolution: usize, clip_from_world: Mat44, max_depth: f32) -> ShadowMap {
+        let size = resolution as f32;
+        ShadowMap::new(resolution, Mat44::viewport(0.0, 0.0, size, size, max_depth) * clip_from_world)
+    }
+
+    // Builds the filterable maps needed by the filter, once the depth is rendered
+    pub fn prefilter(&mut self) {
+        let empty = f32::MIN;
+        self.reference_depth = self.depth.data().iter().cloned().filter(|&d| d > empty).fold(f32::MAX, f32::min);
+        if self.reference_depth == f32::MAX {
+            self.reference_depth = 0.0;
+        }
+
+        // Texels without occluders are as far as the farthest occluder
+        let (reference_depth, depth_per_unit) = (self.reference_depth, self.depth_per_unit);
+        let distance = |d: f32| if d > empty { (d - reference_depth) / depth_per_unit } else { 0.0 };
+
+        let (w, h) = (self.depth.width, self.depth.height);
+        self.prefiltered = match self.filter {
+            _ if !self.orthographic => Prefiltered::None,
+            ShadowFilter::Variance { blur_radius, .. } => {
+                let mut moments = Framebuffer::new(w, h, Vec2f::default());
+                for y in 0..h {
+                    for x in 0..w {
+                        let d = distance(self.depth.get(x, y));
+                        *moments.get_mut(x, y) = Vec2f::new(d, d * d);
+                    }
+                }
+                Prefiltered::Moments(blur(&moments, blur_radius))
+            }
+            ShadowFilter::Exponential { blur_radius, exponent } => {
+                let mut exponential = Framebuffer::new(w, h, 0.0);
+                for y in 0..h {
+                    for x in 0..w {
+                        *exponential.get_mut(x, y) = (-exponent * distance(self.depth.get(x, y))).exp();
+                    }
+                }
+                Prefiltered::Exponential(blur(&exponential, blur_radius))
+            }
+            _ => Prefiltered::None,
+        };
+        self.prefiltered_filter = Some(self.filter);
+    }
+
+    // Position of a world space point in the shadow map
+    pub fn project(&self, world_pos: Vec3f) -> Vec3f {
+        (self.light_from_world * Vec4f::from_vec3f(world_pos, 1.0)).homogenize()
+    }
+
+    // Depth of the closest occluder at a shadow map position, None outside of the map
+    pub fn occluder_depth(&self, map_pos: Vec3f) -> Option<f32> {
+        let (x, y) = (map_pos.x.floor(), map_pos.y.floor());
+        if x >= 0.0 && y >= 0.0 && x < self.depth.width as f32 && y < self.depth.height as f32 {
+            Some(self.depth.get(x as usize, y as usize))
+        } else {
+            None
+        }
+    }
+
+    // 1 when the map position is lit, 0 when it is in shadow. Points outside
+    // of the map are lit. Depth bias is applied when rendering the map.
+    fn compare(&self, map_pos: Vec3f) -> f32 {
+        match self.occluder_depth(map_pos) {
+            Some(depth) if depth > map_pos.z => 0.0,
+            _ => 1.0,
+        }
+    }
+
+    // Random rotation of the Poisson disk, so that the banding of the
+    // few samples turns into noise. Interleaved gradient noise on the texels.
+    fn disk_rotation(map_pos: Vec3f) -> (f32, f32) {
+        let noise = (52.982_918 * (0.067_110_56 * map_pos.x.floor() + 0.005_837_15 * map_pos.y.floor()).fract()).fract();
+        let angle = noise * std::f32::consts::PI * 2.0;
+        (angle.cos(), angle.sin())
+    }
+
+    fn disk_samples(map_pos: Vec3f, radius: f32) -> impl Iterator<Item = Vec2f> {
+        let (cos, sin) = ShadowMap::disk_rotation(map_pos);
+        POISSON_DISK.iter().map(move |&(x, y)| {
+            Vec2f::new(map_pos.x + (x * cos - y * sin) * radius, map_pos.y + (x * sin + y * cos) * radius)
+        })
+    }
+
+    fn pcf(&self, map_pos: Vec3f, kernel: usize) -> f32 {
+        let half = (kernel as f32 - 1.0) / 2.0;
+        let mut lit = 0.0;
+        for j in 0..kernel {
+            for i in 0..kernel {
+                let offset = Vec3f::new(i as f32 - half, j as f32 - half, 0.0);
+                lit += self.compare(map_pos + offset);
+            }
+        }
+        lit / (kernel * kernel) as f32
+    }
+
+    fn poisson(&self, map_pos: Vec3f, radius: f32) -> f32 {
+        let lit: f32 = ShadowMap::disk_samples(map_pos, radius)
+            .map(|p| self.compare(Vec3f::new(p.x, p.y, map_pos.z)))
+            .sum();
+        lit / POISSON_DISK.len() as f32
+    }
+
+    fn pcss(&self, map_pos: Vec3f, light_size: f32) -> f32 {
+        // Blocker search, over the region of the map from which occluders up
+        // to one world unit away can shadow the point
+        let search_radius = (light_size * self.texels_per_unit).clamp(1.0, MAX_PENUMBRA);
+        let (mut blocker_depth, mut blockers) = (0.0, 0);
+        for p in ShadowMap::disk_samples(map_pos, search_radius) {
+            if let Some(depth) = self.occluder_depth(Vec3f::new(p.x, p.y, map_pos.z)) {
+                if depth > map_pos.z {
+                    blocker_depth += depth;
+                    blockers += 1;
+                }
+            }
+        }
+        if blockers == 0 {
+            return 1.0;
+        }
+        let blocker_depth = blocker_depth / blockers as f32;
+
+        // Penumbra of a directional light, similar triangles between the
+        // light, the blocker and the receiver
+        let distance = (blocker_depth - map_pos.z) / self.depth_per_unit;
+        let penumbra = (distance * light_size * self.texels_per_unit).clamp(0.5, MAX_PENUMBRA);
+        self.poisson(map_pos, penumbra)
+    }
+
+    fn variance(&self, moments: &Framebuffer<Vec2f>, map_pos: Vec3f, light_bleeding: f32) -> f32 {
+        let m = match sample_bilinear(moments, map_pos.xy()) {
+            Some(m) => m,
+            None => return 1.0,
+        };
+
+        // Greater depth is closer to the light
+        let t = (map_pos.z - self.reference_depth) / self.depth_per_unit;
+        if t >= m.x {
+            return 1.0;
+        }
+
+        let variance = (m.y - m.x * m.x).max(MIN_VARIANCE);
+        let d = m.x - t;
+        let p_max = variance / (variance + d * d);
+        ((p_max - light_bleeding) / (1.0 - light_bleeding)).clamp(0.0, 1.0)
+    }
+
+    fn exponential(&self, exponential: &Framebuffer<f32>, map_pos: Vec3f, exponent: f32) -> f32 {
+        match sample_bilinear(exponential, map_pos.xy()) {
+            Some(e) => {
+                // exp(c * (t - d)) computed in log space, e can underflow to 0
+                let t = (map_pos.z - self.reference_depth) / self.depth_per_unit;
+                (e.ln() + exponent * t).exp().min(1.0)
+            }
+            None => 1.0,
+        }
+    }
+
+    // Fraction of the light reaching a world space point, from 0 in shadow
+    // to 1 when fully lit. Variance and exponential filters need `prefilter`
+    // to have run with the current filter: debug builds panic otherwise, and
+    // release builds fall back to a 3x3 PCF. They, and PCSS, need an
+    // orthographic map, perspective maps handle them the same way.
+    pub fn visibility(&self, world_pos: Vec3f) -> f32 {
+        debug_assert!(
+            self.orthographic || !self.filter.needs_orthographic_map(),
+            "Perspective shadow maps don't support {:?}",
+            self.filter
+        );
+        let map_pos = self.project(world_pos);
+        let prefiltered = self.prefiltered_filter == Some(self.filter);
+        match (self.filter, &self.prefiltered) {
+            (ShadowFilter::Hard, _) => self.compare(map_pos),
+            (ShadowFilter::Pcf { kernel }, _) => self.pcf(map_pos, kernel),
+            (ShadowFilter::Poisson { radius }, _) => self.poisson(map_pos, radius),
+            _ if !self.orthographic => self.pcf(map_pos, 3),
+            (ShadowFilter::Pcss { light_size }, _) => self.pcss(map_pos, light_size),
+            (ShadowFilter::Variance { light_bleeding, .. }, Prefiltered::Moments(moments)) if prefiltered => {
+                self.variance(moments, map_pos, light_bleeding)
+            }
+            (ShadowFilter::Exponential { exponent, .. }, Prefiltered::Exponential(exponential)) if prefiltered => {
+                self.exponential(exponential, map_pos, exponent)
+            }
+            _ => {
+                debug_assert!(prefiltered, "Shadow map filter {:?} used without its prefilter", self.filter);
+                self.pcf(map_pos, 3)
+            }
+        }
+    }
+}
+
+// Camera the cascades are fitted to, with the perspective of
+// `Mat44::projection`. Depths are distances from the eye along the view
+// direction.
+#[derive(Debug, Clone, Copy)]
+pub struct ViewFrustum {
+    pub camera_from_world: Mat44,
+    // Coefficient of `Mat44::projection`, -1 / distance from the eye to the
+    // origin of the camera space
+    pub projection_coef: f32,
+    // Half extent of the visible screen, in normalized device coordinates
+    pub ndc_extent: f32,
+}
+
+impl ViewFrustum {
+    pub fn depth(&self, world_pos: Vec3f) -> f32 {
+        let z = (self.camera_from_world * Vec4f::from_vec3f(world_pos, 1.0)).z;
+        (1.0 + self.projection_coef * z) / -self.projection_coef
+    }
+
+    // World space corners of the visible screen at a depth
+    fn corners(&self, world_from_camera: Mat44, depth: f32) -> [Vec3f; 4] {
+        let w = -self.projection_coef * depth;
+        let z = (w - 1.0) / self.projection_coef;
+        let e = self.ndc_extent * w;
+        let corner = |x: f32, y: f32| (world_from_camera * Vec4f::new(x, y, z, 1.0)).xyz();
+        [corner(-e, -e), corner(e, -e), corner(e, e), corner(-e, e)]
+    }
+}
+
+// Axis aligned bounds of points transformed by a matrix without projection
+fn transformed_bounds(m: Mat44, points: &[Vec3f]) -> (Vec3f, Vec3f) {
+    let mut min = Vec3f::new(f32::MAX, f32::MAX, f32::MAX);
+    let mut max = Vec3f::new(f32::MIN, f32::MIN, f32::MIN);
+    for &p in points {
+        let p = (m * Vec4f::from_vec3f(p, 1.0)).xyz();
+        min = Vec3f::new(min.x.min(p.x), min.y.min(p.y), min.z.min(p.z));
+        max = Vec3f::new(max.x.max(p.x), max.y.max(p.y), max.z.max(p.z));
+    }
+    (min, max)
+}
+
+fn box_corners(min: Vec3f, max: Vec3f) -> [Vec3f; 8] {
+    let mut corners = [min; 8];
+    for (i, c) in corners.iter_mut().enumerate() {
+        *c = Vec3f::new(
+            if i & 1 == 0 { min.x } else { max.x },
+            if i & 2 == 0 { min.y } else { max.y },
+            if i & 4 == 0 { min.z } else { max.z },
+        );
+    }
+    corners
+}
+
+// Shadow maps of a directional light, each covering a depth slice of the view
+// frustum with a tight orthographic projection
+pub struct CascadedShadowMap {
+    pub cascades: Vec<ShadowMap>,
+    // Far end of each cascade, as a view depth
+    pub splits: Vec<f32>,
+    view: ViewFrustum,
+    blend: f32,
+}
+
+impl CascadedShadowMap {
+    // The cascades cover the part of the view frustum inside the scene bounds,
+    // and every occluder of the scene. `light_dir` points towards the light.
+    pub fn new(settings: &ShadowSettings, view: ViewFrustum, light_dir: Vec3f, scene_bounds: (Vec3f, Vec3f), max_depth: f32) -> CascadedShadowMap {
+        assert!(view.projection_coef < 0.0, "Cascades need a perspective projection");
+        assert!(settings.cascades > 0);
+
+        let scene_corners = box_corners(scene_bounds.0, scene_bounds.1);
+        let depths: Vec<f32> = scene_corners.iter().map(|&p| view.depth(p)).collect();
+        let far = depths.iter().cloned().fold(f32::MIN, f32::max);
+        let near = depths.iter().cloned().fold(f32::MAX, f32::min).max(far * 0.01);
+
+        // Practical split scheme, between the logarithmic and uniform splits
+        let count = settings.cascades;
+        let splits: Vec<f32> = (1..=count)
+            .map(|i| {
+                let f = i as f32 / count as f32;
+                let log = near * (far / near).powf(f);
+                let uniform = near + (far - near) * f;
+                settings.split_lambda * log + (1.0 - settings.split_lambda) * uniform
+            })
+            .collect();
+
+        let light_rotation = Mat44::lookat(light_dir, Vec3f::new(0.0, 0.0, 0.0), light_up(light_dir));
+        let world_from_camera = view.camera_from_world.inverse();
+        let (scene_min, scene_max) = transformed_bounds(light_rotation, &scene_corners);
+
+        let size = settings.resolution as f32;
+        let cascades = splits
+            .iter()
+            .enumerate()
+            .map(|(i, &split)| {
+                let slice_near = if i == 0 { near } else { splits[i - 1] };
+                let mut corners = view.corners(world_from_camera, slice_near).to_vec();
+                corners.extend_from_slice(&view.corners(world_from_camera, split));
+                let (min, max) = transformed_bounds(light_rotation, &corners);
+
+                // Square fit, clipped to the scene, with a margin for the filters
+                let (xmin, xmax) = (min.x.max(scene_min.x), max.x.min(scene_max.x));
+                let (ymin, ymax) = (min.y.max(scene_min.y), max.y.min(scene_max.y));
+                let extent = (xmax - xmin).max(ymax - ymin).max(1e-3) * 1.05;
+
+                // Snapped to the texels, so that the shadows don't shimmer
+                let texel = extent / size;
+                let cx = ((xmin + xmax) * 0.5 / texel).floor() * texel;
+                let cy = ((ymin + ymax) * 0.5 / texel).floor() * texel;
+
+                // Every occluder of the scene is in the depth range, closer to
+                // the light means greater depth
+                let depth_margin = (scene_max.z - scene_min.z).max(1e-3) * 0.01;
+                let (zmin, zmax) = (scene_min.z - depth_margin, scene_max.z + depth_margin);
+
+                let mut map_from_light = Mat44::identity();
+                map_from_light.m[0][0] = size / extent;
+                map_from_light.m[0][3] = (extent * 0.5 - cx) * size / extent;
+                map_from_light.m[1][1] = size / extent;
+                map_from_light.m[1][3] = (extent * 0.5 - cy) * size / extent;
+                map_from_light.m[2][2] = max_depth / (zmax - zmin);
+                map_from_light.m[2][3] = -zmin * max_depth / (zmax - zmin);
+
+                let mut map = ShadowMap::new(settings.resolution, map_from_light * light_rotation);
+                map.filter = settings.filter;
+                map
+            })
+            .collect();
+
+        CascadedShadowMap {
+            cascades,
+            splits,
+            view,
+            blend: settings.cascade_blend,
+        }
+    }
+
+    // Index of the cascade covering a world space point, None past the last one
+    pub fn cascade_index(&self, world_pos: Vec3f) -> Option<usize> {
+        let depth = self.view.depth(world_pos);
+        self.splits.iter().position(|&split| depth <= split)
+    }
+
+    // Visibility from the cascade covering the point, blended with the next
+    // cascade close to its far end. Points past the last cascade are lit.
+    pub fn visibility(&self, world_pos: Vec3f) -> f32 {
+        let i = match self.cascade_index(world_pos) {
+            Some(i) => i,
+            None => return 1.0,
+        };
+        let visibility = self.cascades[i].visibility(world_pos);
+        if i + 1 == self.cascades.len() {
+            return visibility;
+        }
+
+        let start = if i == 0 { 0.0 } else { self.splits[i - 1] };
+        let blend_start = self.splits[i] - (self.splits[i] - start) * self.blend;
+        let depth = self.view.depth(world_pos);
+        if depth <= blend_start {
+            return visibility;
+        }
+
+        let t = (depth - blend_start) / (self.splits[i] - blend_start);
+        visibility * (1.0 - t) + self.cascades[i + 1].visibility(world_pos) * t
+    }
+}
+
+// Forward and up directions of the cube faces: +x, -x, +y, -y, +z, -z
+const CUBE_FACES: [([f32; 3], [f32; 3]); 6] = [
+    ([1.0, 0.0, 0.0], [0.0, 1.0, 0.0]),
+    ([-1.0, 0.0, 0.0], [0.0, 1.0, 0.0]),
+    ([0.0, 1.0, 0.0], [0.0, 0.0, -1.0]),
+    ([0.0, -1.0, 0.0], [0.0, 0.0, 1.0]),
+    ([0.0, 0.0, 1.0], [0.0, 1.0, 0.0]),
+    ([0.0, 0.0, -1.0], [0.0, 1.0, 0.0]),
+];
+
+pub fn cube_face_basis(face: usize) -> (Vec3f, Vec3f, Vec3f) {
+    let (f, u) = CUBE_FACES[face];
+    let (forward, up) = (Vec3f::new(f[0], f[1], f[2]), Vec3f::new(u[0], u[1], u[2]));
+    // Right handed like the camera, so that the winding is kept
+    (forward.cross(up), up, forward)
+}
+
+// Face of the cube a direction goes through, along its major axis
+pub fn cube_face(dir: Vec3f) -> usize {
+    let (x, y, z) = (dir.x.abs(), dir.y.abs(), dir.z.abs());
+    if x >= y && x >= z {
+        if dir.x >= 0.0 { 0 } else { 1 }
+    } else if y >= z {
+        if dir.y >= 0.0 { 2 } else { 3 }
+    } else if dir.z >= 0.0 {
+        4
+    } else {
+        5
+    }
+}
+
+// Omnidirectional shadow map of a point light: six 90 degree perspective
+// shadow maps, sampled by direction from the light. Depth is `near / distance`
+// along the axis of the face, scaled to the max depth, so that it is greater
+// closer to the light without needing a far plane.
+pub struct CubeShadowMap {
+    // In the order of `CUBE_FACES`
+    pub faces: Vec<ShadowMap>,
+    pub position: Vec3f,
+    pub filter: ShadowFilter,
+}
+
+impl CubeShadowMap {
+    pub fn new(resolution: usize, position: Vec3f, near: f32, max_depth: f32) -> CubeShadowMap {
+        let half = resolution as f32 / 2.0;
+        let faces = (0..6)
+            .map(|face| {
+                let (right, up, forward) = cube_face_basis(face);
+                // x and y in [-w, w] go to [0, resolution], w the distance along the face axis
+                let x = right * half + forward * half;
+                let y = up * half + forward * half;
+                let light_from_world = Mat44::new(
+                    x.x, x.y, x.z, -x.dot(position),
+                    y.x, y.y, y.z, -y.dot(position),
+                    0.0, 0.0, 0.0, near * max_depth,
+                    forward.x, forward.y, forward.z, -forward.dot(position),
+                );
+                ShadowMap::new(resolution, light_from_world)
+            })
+            .collect();
+
+        CubeShadowMap {
+            faces,
+            position,
+            filter: ShadowFilter::Hard,
+        }
+    }
+
+    // Depth comparison in the face the point is seen through, points on the
+    // border of a face are kept inside of it
+    fn compare(&self, world_pos: Vec3f) -> f32 {
+        let face = &self.faces[cube_face(world_pos - self.position)];
+        let max = face.depth.width as f32 - 0.5;
+        let map_pos = face.project(world_pos);
+        face.compare(Vec3f::new(map_pos.x.clamp(0.0, max), map_pos.y.clamp(0.0, max), map_pos.z))
+    }
+
+    // Average of the comparisons at offsets around the point, in texels of
+    // its face. Offsets going past the face are looked up in the next face.
+    fn filtered(&self, world_pos: Vec3f, offsets: impl Iterator<Item = Vec2f>) -> f32 {
+        let dir = world_pos - self.position;
+        let (right, up, forward) = cube_face_basis(cube_face(dir));
+        let texel = 2.0 * forward.dot(dir) / self.faces[0].depth.width as f32;
+
+        let (mut lit, mut count) = (0.0, 0);
+        for offset in offsets {
+            lit += self.compare(world_pos + right * (offset.x * texel) + up * (offset.y * texel));
+            count += 1;
+        }
+        lit / count as f32
+    }
+
+    // Fraction of the light reaching a world space point. The filters needing
+    // an orthographic map aren't supported: debug builds panic on them, and
+    // release builds fall back to a 3x3 PCF.
+    pub fn visibility(&self, world_pos: Vec3f) -> f32 {
+        debug_assert!(!self.filter.needs_orthographic_map(), "Cube shadow maps don't support {:?}", self.filter);
+        let kernel = match self.filter {
+            ShadowFilter::Hard => return self.compare(world_pos),
+            ShadowFilter::Poisson { radius } => {
+                let map_pos = self.faces[cube_face(world_pos - self.position)].project(world_pos);
+                let (cos, sin) = ShadowMap::disk_rotation(map_pos);
+                let disk = POISSON_DISK
+                    .iter()
+                    .map(|&(x, y)| Vec2f::new(x * cos - y * sin, x * sin + y * cos) * radius);
+                return self.filtered(world_pos, disk);
+            }
+            ShadowFilter::Pcf { kernel } => kernel,
+            _ => 3,
+        };
+        let half = (kernel as f32 - 1.0) / 2.0;
+        let grid = (0..kernel * kernel).map(|i| Vec2f::new((i % kernel) as f32 - half, (i / kernel) as f32 - half));
+        self.filtered(world_pos, grid)
+    }
+}
+
+// Shadow map of a light, of the kind its shape needs
+pub enum LightShadow {
+    None,
+    Cascaded(CascadedShadowMap),
+    Cube(CubeShadowMap),
+    Perspective(ShadowMap),
+}
+
+impl LightShadow {
+    // Depth maps to render
+    pub fn maps_mut(&mut self) -> Vec<&mut ShadowMap> {
+        match self {
+            LightShadow::None => Vec::new(),
+            LightShadow::Cascaded(csm) => csm.cascades.iter_mut().collect(),
+            LightShadow::Cube(cube) => cube.faces.iter_mut().collect(),
+            LightShadow::Perspective(map) => vec![map],
+        }
+    }
+
+    pub fn visibility(&self, world_pos: Vec3f) -> f32 {
+        match self {
+            LightShadow::None => 1.0,
+            LightShadow::Cascaded(csm) => csm.visibility(world_pos),
+            LightShadow::Cube(cube) => cube.visibility(world_pos),
+            LightShadow::Perspective(map) => map.visibility(world_pos),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use light::SpotLight;
+
+    #[test]
+    fn texel_addressing_test() {
+        let mut shadow_map = ShadowMap::new(4, Mat44::identity());
+        *shadow_map.depth.get_mut(3, 1) = 10.0;
+
+        assert_eq!(shadow_map.occluder_depth(Vec3f::new(3.9, 1.2, 0.0)), Some(10.0));
+        assert_eq!(shadow_map.occluder_depth(Vec3f::new(1.2, 3.9, 0.0)), Some(f32::MIN));
+        assert_eq!(shadow_map.occluder_depth(Vec3f::new(4.0, 1.0, 0.0)), None);
+        assert_eq!(shadow_map.occluder_depth(Vec3f::new(-0.5, 1.0, 0.0)), None);
+
+        assert_eq!(shadow_map.visibility(Vec3f::new(3.5, 1.5, 5.0)), 0.0);
+        assert_eq!(shadow_map.visibility(Vec3f::new(3.5, 1.5, 10.0)), 1.0);
+        assert_eq!(shadow_map.visibility(Vec3f::new(2.5, 1.5, 5.0)), 1.0);
+        assert_eq!(shadow_map.visibility(Vec3f::new(-3.5, 1.5, 5.0)), 1.0);
+    }
+
+    #[test]
+    fn filtered_visibility_test() {
+        // Occluder covering the left half of the map
+        let mut shadow_map = ShadowMap::new(16, Mat44::identity());
+        for y in 0..16 {
+            for x in 0..8 {
+                *shadow_map.depth.get_mut(x, y) = 10.0;
+            }
+        }
+
+        for &filter in [
+            ShadowFilter::Pcf { kernel: 3 },
+            ShadowFilter::Poisson { radius: 2.0 },
+            ShadowFilter::Pcss { light_size: 0.2 },
+        ]
+        .iter()
+        {
+            shadow_map.filter = filter;
+            assert_eq!(shadow_map.visibility(Vec3f::new(2.5, 8.5, 5.0)), 0.0, "{:?}", filter);
+            assert_eq!(shadow_map.visibility(Vec3f::new(13.5, 8.5, 5.0)), 1.0, "{:?}", filter);
+            let edge = shadow_map.visibility(Vec3f::new(8.0, 8.5, 5.0));
+            assert!(edge > 0.0 && edge < 1.0, "{:?}", filter);
+        }
+
+        shadow_map.filter = ShadowFilter::Pcf { kernel: 3 };
+        assert_eq!(shadow_map.visibility(Vec3f::new(8.5, 8.5, 5.0)), 2.0 / 3.0);
+    }
+
+    #[test]
+    fn prefiltered_visibility_test() {
+        // Occluder covering the left half of the map, over a receiver
+        let mut shadow_map = ShadowMap::new(16, Mat44::identity());
+        for y in 0..16 {
+            for x in 0..16 {
+                *shadow_map.depth.get_mut(x, y) = if x < 8 { 10.0 } else { 5.0 };
+            }
+        }
+
+        for &filter in [
+            ShadowFilter::Variance { blur_radius: 2, light_bleeding: 0.2 },
+            ShadowFilter::Exponential { blur_radius: 2, exponent: 10.0 },
+        ]
+        .iter()
+        {
+            shadow_map.filter = filter;
+            shadow_map.prefilter();
+            assert!(shadow_map.visibility(Vec3f::new(2.5, 8.5, 5.0)) < 0.01, "{:?}", filter);
+            assert!(shadow_map.visibility(Vec3f::new(13.5, 8.5, 5.0)) > 0.999, "{:?}", filter);
+            assert_eq!(shadow_map.visibility(Vec3f::new(20.0, 8.5, 5.0)), 1.0, "{:?}", filter);
+            let edge = shadow_map.visibility(Vec3f::new(8.0, 8.5, 5.0));
+            assert!(edge > 0.0 && edge < 1.0, "{:?} {}", filter, edge);
+        }
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "without its prefilter")]
+    fn stale_prefilter_test() {
+        let mut shadow_map = ShadowMap::new(16, Mat44::identity());
+        shadow_map.filter = ShadowFilter::Variance { blur_radius: 2, light_bleeding: 0.2 };
+        shadow_map.prefilter();
+        shadow_map.visibility(Vec3f::new(8.0, 8.5, 5.0));
+
+        // The moments were blurred for the previous radius
+        shadow_map.filter = ShadowFilter::Variance { blur_radius: 4, light_bleeding: 0.2 };
+        shadow_map.visibility(Vec3f::new(8.0, 8.5, 5.0));
+    }
+
+    #[test]
+    fn cascades_test() {
+        let eye = Vec3f::new(0.0, 0.0, 4.0);
+        let up = Vec3f::new(0.0, 1.0, 0.0);
+        let view = ViewFrustum {
+            camera_from_world: Mat44::lookat(eye, Vec3f::new(0.0, 0.0, 0.0), up),
+            projection_coef: -1.0 / 4.0,
+            ndc_extent: 1.0,
+        };
+        assert!((view.depth(Vec3f::new(0.0, 0.0, 0.0)) - 4.0).abs() < 1e-5);
+        assert!((view.depth(Vec3f::new(1.0, 2.0, -3.0)) - 7.0).abs() < 1e-5);
+
+        let settings = ShadowSettings {
+            cascades: 3,
+            resolution: 64,
+            ..ShadowSettings::default()
+        };
+        let bounds = (Vec3f::new(-2.0, -1.0, -6.0), Vec3f::new(2.0, 1.0, 2.0));
+        let light_dir = Vec3f::new(1.0, 1.0, 0.0).normalized();
+        let csm = CascadedShadowMap::new(&settings, view, light_dir, bounds, 2000.0);
+
+        assert_eq!(csm.cascades.len(), 3);
+        assert!(csm.splits[0] < csm.splits[1] && csm.splits[1] < csm.splits[2]);
+        assert!((csm.splits[2] - 10.0).abs() < 1e-4);
+
+        // Visible points are inside the map of their cascade
+        for &p in [Vec3f::new(0.0, 0.0, 1.5), Vec3f::new(0.5, -0.5, -1.0), Vec3f::new(-1.5, 0.8, -5.0)].iter() {
+            let i = csm.cascade_index(p).unwrap();
+            let map_pos = csm.cascades[i].project(p);
+            assert!(csm.cascades[i].occluder_depth(map_pos).is_some(), "{} in cascade {}", p.z, i);
+            assert!(map_pos.z > 0.0 && map_pos.z < 2000.0);
+        }
+        assert!(csm.cascade_index(Vec3f::new(0.0, 0.0, -7.0)).is_none());
+    }
+
+    #[test]
+    fn cube_shadow_map_test() {
+        let position = Vec3f::new(1.0, 2.0, 3.0);
+        let cube = CubeShadowMap::new(64, position, 0.1, 2000.0);
+
+        // Every direction ends up inside the map of its face, closer is deeper
+        for &dir in [
+            Vec3f::new(1.0, 0.2, -0.3),
+            Vec3f::new(-1.0, 0.9, 0.9),
+            Vec3f::new(0.1, 1.0, 0.0),
+            Vec3f::new(0.3, -1.0, 0.2),
+            Vec3f::new(-0.5, 0.5, 1.0),
+            Vec3f::new(0.0, 0.0, -1.0),
+        ]
+        .iter()
+        {
+            let face = &cube.faces[cube_face(dir)];
+            let near = face.project(position + dir);
+            let far = face.project(position + dir * 2.0);
+            assert!(face.occluder_depth(near).is_some());
+            assert!((near.x - far.x).abs() < 1e-3 && (near.y - far.y).abs() < 1e-3);
+            assert!(near.z > far.z);
+        }
+
+        // The faces are seen from the inside of the cube with the winding of
+        // the camera, counter clockwise on screen for a triangle facing the light
+        for face in 0..6 {
+            let (right, up, forward) = cube_face_basis(face);
+            let (a, b, c) = (forward, forward + right * 0.1, forward + up * 0.1);
+            let m = cube.faces[face].light_from_world;
+            let p = |v: Vec3f| (m * Vec4f::from_vec3f(position + v, 1.0)).homogenize().xy();
+            assert!(::raster::signed_area(p(a), p(b), p(c)) > 0.0);
+        }
+    }
+
+    #[test]
+    fn perspective_shadow_map_test() {
+        let position = Vec3f::new(1.0, 3.0, 0.0);
+        let direction = Vec3f::new(0.0, -1.0, 0.2);
+        let mut light = SpotLight::new(position, direction);
+        light.outer_angle = 0.5;
+        let map = ShadowMap::perspective(64, light.clip_from_world(0.1), 2000.0);
+
+        // The axis of the light goes through the center of the map
+        let near = map.project(position + direction.normalized());
+        let far = map.project(position + direction.normalized() * 5.0);
+        assert!((near.x - 32.0).abs() < 1e-3 && (near.y - 32.0).abs() < 1e-3);
+        assert!((far.x - 32.0).abs() < 1e-3 && (far.y - 32.0).abs() < 1e-3);
+        assert!(near.z > far.z && far.z > 0.0 && near.z < 2000.0);
+
+        let mut map = map;
+        map.filter = ShadowFilter::Poisson { radius: 2.0 };
+        map.prefilter();
+        assert_eq!(map.visibility(position + direction), 1.0);
+    }
+}
@@ -0,0 +1,140 @@
+use color;
+use framebuffer::Framebuffer;
+use ibl;
+use math::{Mat44, Vec2f, Vec3f, Vec4f};
+use shadow;
+use stb_image::image;
+
+// Background of the scene, seen through the pixels that no geometry
+// covers. Colors are linear.
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SkySettings {
+    // The environment map lighting the scene
+    Environment,
+    // Equirectangular map in the resource directory, HDR or sRGB
+    Map(String),
+    // Six images in the resource directory, in the order +x, -x, +y, -y,
+    // +z, -z. Each face is seen from the center of the cube, looking along
+    // its axis with y up, and with -z up for the top and +z up for the
+    // bottom faces.
+    Cube(Vec<String>),
+    // Procedural sky fading from the horizon color to the zenith color
+    // upwards and to the ground color downwards
+    Gradient { zenith: Vec3f, horizon: Vec3f, ground: Vec3f },
+}
+
+// Linear map from a sRGB image loaded by stb_image, whose rows are stored
+// from top to bottom
+pub fn from_srgb_image(image: &image::Image<u8>) -> Framebuffer<Vec3f> {
+    let mut map = Framebuffer::new(image.width, image.height, Vec3f::default());
+    for y in 0..image.height {
+        for x in 0..image.width {
+            let i = ((image.height - 1 - y) * image.width + x) * image.depth;
+            let c = if image.depth >= 3 {
+                color::decode_srgb8(image.data[i], image.data[i + 1], image.data[i + 2])
+            } else {
+                color::decode_srgb8(image.data[i], image.data[i], image.data[i])
+            };
+            map.set(x, y, c);
+        }
+    }
+    map
+}
+
+// Bilinear lookup, clamped to the edges
+fn sample_clamped(map: &Framebuffer<Vec3f>, uv: Vec2f) -> Vec3f {
+    let x = (uv.x * map.width as f32 - 0.5).clamp(0.0, (map.width - 1) as f32);
+    let y = (uv.y * map.height as f32 - 0.5).clamp(0.0, (map.height - 1) as f32);
+    let (x0, y0) = (x.floor() as usize, y.floor() as usize);
+    let (x1, y1) = ((x0 + 1).min(map.width - 1), (y0 + 1).min(map.height - 1));
+    let (tx, ty) = (x.fract(), y.fract());
+    let row = |y: usize| map.get(x0, y) * (1.0 - tx) + map.get(x1, y) * tx;
+    row(y0) * (1.0 - ty) + row(y1) * ty
+}
+
+pub enum Sky {
+    // Equirectangular
+    Map(Framebuffer<Vec3f>),
+    // In the order of `shadow::CUBE_FACES`
+    Cube(Vec<Framebuffer<Vec3f>>),
+    Gradient { zenith: Vec3f, horizon: Vec3f, ground: Vec3f },
+}
+
+impl Sky {
+    // Radiance coming from the direction `dir`
+    pub fn radiance(&self, dir: Vec3f) -> Vec3f {
+        match self {
+            Sky::Map(map) => ibl::sample(map, ibl::direction_to_uv(dir)),
+            Sky::Cube(faces) => {
+                let face = shadow::cube_face(dir);
+                let (right, up, forward) = shadow::cube_face_basis(face);
+                let d = dir * (1.0 / dir.dot(forward));
+                sample_clamped(&faces[face], Vec2f::new(0.5 + 0.5 * d.dot(right), 0.5 + 0.5 * d.dot(up)))
+            }
+            Sky::Gradient { zenith, horizon, ground } => {
+                // Square root of the sine of the elevation, for a wider
+                // horizon band
+                let y = dir.normalized().y;
+                let t = y.abs().sqrt();
+                let end = if y >= 0.0 { *zenith } else { *ground };
+                *horizon * (1.0 - t) + end * t
+            }
+        }
+    }
+
+    // Fills the samples of the color buffer whose depth is still `clear_depth`
+    // with the sky seen through them. The view directions go from the eye to
+    // the pixels unprojected with the inverse of the transform from world
+    // space to the screen, at the depth of `target`, in front of the eye.
+    pub fn render(&self, screen_from_world: Mat44, eye: Vec3f, target: Vec3f, clear_depth: f32, z_buffer: &Framebuffer<f32>, color_buffer: &mut Framebuffer<Vec4f>) {
+        assert_eq!(color_buffer.samples, z_buffer.samples);
+        let world_from_screen = screen_from_world.inverse();
+        let depth = (screen_from_world * Vec4f::from_vec3f(target, 1.0)).homogenize().z;
+
+        for y in 0..z_buffer.height {
+            for x in 0..z_buffer.width {
+                let is_clear = |s: usize| z_buffer.get_sample(x, y, s) == clear_depth;
+                if !(0..z_buffer.samples).any(is_clear) {
+                    continue;
+                }
+
+                let screen = Vec4f::new(x as f32 + 0.5, y as f32 + 0.5, depth, 1.0);
+                let world = (world_from_screen * screen).homogenize();
+                let color = Vec4f::from_vec3f(self.radiance(world - eye), 1.0);
+                for s in (0..z_buffer.samples).filter(|&s| is_clear(s)) {
+                    *color_buffer.get_sample_mut(x, y, s) = color;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gradient_sky_test() {
+        let (zenith, horizon, ground) = (Vec3f::new(0.0, 0.0, 1.0), Vec3f::new(1.0, 1.0, 1.0), Vec3f::new(0.0, 1.0, 0.0));
+        let sky = Sky::Gradient { zenith, horizon, ground };
+        assert_eq!(sky.radiance(Vec3f::new(0.0, 2.0, 0.0)), zenith);
+        assert_eq!(sky.radiance(Vec3f::new(1.0, 0.0, 0.0)), horizon);
+        assert_eq!(sky.radiance(Vec3f::new(0.0, -1.0, 0.0)), ground);
+
+        // Looking down -z from the origin, the sky only goes where the
+        // z-buffer is clear, the top row sees above the horizon
+        let eye = Vec3f::new(0.0, 0.0, 0.0);
+        let screen_from_world = Mat44::viewport(0.0, 0.0, 4.0, 4.0, 2.0) * Mat44::perspective(1.5, 1.0, 0.1, 10.0);
+        let mut z_buffer = Framebuffer::new_multisample(4, 4, 2, f32::MIN);
+        *z_buffer.get_sample_mut(0, 3, 1) = 0.0;
+        let mut color_buffer = Framebuffer::new_multisample(4, 4, 2, Vec4f::new(0.0, 0.0, 0.0, 0.0));
+        sky.render(screen_from_world, eye, Vec3f::new(0.0, 0.0, -1.0), f32::MIN, &z_buffer, &mut color_buffer);
+
+        assert_eq!(color_buffer.get_sample(0, 3, 1).w, 0.0);
+        let top = color_buffer.get_sample(0, 3, 0);
+        let bottom = color_buffer.get_sample(0, 0, 0);
+        assert!(top.z == 1.0 && top.x < 1.0 && top.w == 1.0);
+        assert!(bottom.y == 1.0 && bottom.x < 1.0);
+    }
+}
@@ -0,0 +1,259 @@
+use framebuffer::Framebuffer;
+use math::{Mat44, Vec2f, Vec3f, Vec4f};
+
+// Screen space ambient occlusion: the occlusion of every pixel is estimated
+// from the depth of the scene around it. Points are sampled in a hemisphere
+// oriented along the normal reconstructed from the depth, in camera space,
+// and are occluded when the scene is in front of them. The kernel is rotated
+// by a tiled noise to trade banding for noise, which a bilateral blur then
+// smooths out without bleeding across depth discontinuities.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SsaoSettings {
+    pub enabled: bool,
+    // Radius of the sampled hemisphere, in world units
+    pub radius: f32,
+    pub samples: usize,
+    // Depth difference under which a sample isn't occluded, against the self
+    // occlusion of flat surfaces
+    pub bias: f32,
+    // Exponent of the accessibility, darkening the occluded areas
+    pub intensity: f32,
+    // Radius of the bilateral blur, in pixels
+    pub blur_radius: usize,
+    // Outputs the occlusion buffer instead of the shaded image
+    pub debug: bool,
+}
+
+impl Default for SsaoSettings {
+    fn default() -> SsaoSettings {
+        SsaoSettings {
+            enabled: false,
+            radius: 0.3,
+            samples: 16,
+            bias: 0.01,
+            intensity: 1.0,
+            blur_radius: 2,
+            debug: false,
+        }
+    }
+}
+
+// Width and height of the tile of kernel rotations
+const NOISE_SIZE: usize = 4;
+
+// Deterministic pseudo random number in [0, 1[
+fn random(seed: u32) -> f32 {
+    let mut x = seed.wrapping_mul(0x9e37_79b9) ^ 0x85eb_ca6b;
+    x ^= x >> 16;
+    x = x.wrapping_mul(0x7feb_352d);
+    x ^= x >> 15;
+    x = x.wrapping_mul(0x846c_a68b);
+    x ^= x >> 16;
+    (x >> 8) as f32 / (1 << 24) as f32
+}
+
+// Points in the unit hemisphere around +z, more of them close to the center
+// where the occluders matter most
+fn kernel(samples: usize) -> Vec<Vec3f> {
+    (0..samples)
+        .map(|i| {
+            let seed = i as u32 * 3;
+            let dir = Vec3f::new(random(seed) * 2.0 - 1.0, random(seed + 1) * 2.0 - 1.0, random(seed + 2)).normalized();
+            let t = (i + 1) as f32 / samples as f32;
+            dir * (0.1 + 0.9 * t * t)
+        })
+        .collect()
+}
+
+// Rotations of the kernel around the normal, as vectors of the xy plane
+fn noise() -> Vec<Vec3f> {
+    (0..NOISE_SIZE * NOISE_SIZE)
+        .map(|i| {
+            let angle = random(1000 + i as u32) * 2.0 * std::f32::consts::PI;
+            Vec3f::new(angle.cos(), angle.sin(), 0.0)
+        })
+        .collect()
+}
+
+fn smoothstep(t: f32) -> f32 {
+    let t = t.clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+pub struct AmbientOcclusion {
+    // Accessibility of the pixels, from 0 when fully occluded to 1
+    pub buffer: Framebuffer<f32>,
+}
+
+impl AmbientOcclusion {
+    // Occlusion of the depth buffer `depth` rendered with the transform
+    // `screen_from_camera`, the samples still at `clear_depth` are background
+    pub fn new(settings: &SsaoSettings, depth: &Framebuffer<f32>, screen_from_camera: Mat44, clear_depth: f32) -> AmbientOcclusion {
+        let (width, height) = (depth.width, depth.height);
+        let camera_from_screen = screen_from_camera.inverse();
+        let unproject = |x: f32, y: f32, z: f32| (camera_from_screen * Vec4f::new(x, y, z, 1.0)).homogenize();
+        let covered = |x: usize, y: usize| depth.get(x, y) != clear_depth;
+
+        let mut positions = Framebuffer::new(width, height, Vec3f::default());
+        for y in 0..height {
+            for x in 0..width {
+                if covered(x, y) {
+                    positions.set(x, y, unproject(x as f32 + 0.5, y as f32 + 0.5, depth.get(x, y)));
+                }
+            }
+        }
+
+        // Difference with the closest neighbour in depth along an axis, not to
+        // straddle the edges
+        let derivative = |x: usize, y: usize, dx: isize, dy: isize| -> Option<Vec3f> {
+            let p = positions.get(x, y);
+            let neighbour = |s: isize| {
+                let (nx, ny) = (x as isize + dx * s, y as isize + dy * s);
+                if nx < 0 || ny < 0 || nx >= width as isize || ny >= height as isize || !covered(nx as usize, ny as usize) {
+                    None
+                } else {
+                    Some((positions.get(nx as usize, ny as usize) - p) * s as f32)
+                }
+            };
+            match (neighbour(1), neighbour(-1)) {
+                (Some(a), Some(b)) => Some(if a.z.abs() < b.z.abs() { a } else { b }),
+                (a, b) => a.or(b),
+            }
+        };
+
+        let kernel = kernel(settings.samples);
+        let noise = noise();
+
+        let mut occlusion = Framebuffer::new(width, height, 1.0);
+        for y in 0..height {
+            for x in 0..width {
+                if !covered(x, y) {
+                    continue;
+                }
+                let p = positions.get(x, y);
+                let normal = match (derivative(x, y, 1, 0), derivative(x, y, 0, 1)) {
+                    (Some(ddx), Some(ddy)) => ddx.cross(ddy).normalized(),
+                    _ => continue,
+                };
+                // Facing the camera, which is where the depth grows
+                let to_camera = unproject(x as f32 + 0.5, y as f32 + 0.5, depth.get(x, y) + 1.0) - p;
+                let normal = if normal.dot(to_camera) < 0.0 { -normal } else { normal };
+
+                let rotation = noise[(y % NOISE_SIZE) * NOISE_SIZE + x % NOISE_SIZE];
+                let tangent = (rotation - normal * rotation.dot(normal)).normalized();
+                let bitangent = normal.cross(tangent);
+
+                let mut occluded = 0.0;
+                for k in kernel.iter() {
+                    let sample = p + (tangent * k.x + bitangent * k.y + normal * k.z) * settings.radius;
+                    let screen = (screen_from_camera * Vec4f::from_vec3f(sample, 1.0)).homogenize();
+                    let (sx, sy) = (screen.x.floor(), screen.y.floor());
+                    if sx < 0.0 || sy < 0.0 || sx >= width as f32 || sy >= height as f32 {
+                        continue;
+                    }
+                    let (sx, sy) = (sx as usize, sy as usize);
+                    if !covered(sx, sy) {
+                        continue;
+                    }
+                    // The camera looks down -z, so the scene is in front of
+                    // the sample when its z is greater
+                    let scene_z = positions.get(sx, sy).z;
+                    if scene_z >= sample.z + settings.bias {
+                        occluded += smoothstep(settings.radius / (p.z - scene_z).abs());
+                    }
+                }
+                let accessibility = 1.0 - occluded / kernel.len() as f32;
+                occlusion.set(x, y, accessibility.max(0.0).powf(settings.intensity));
+            }
+        }
+
+        let depth_of = |x: usize, y: usize| if covered(x, y) { Some(positions.get(x, y).z) } else { None };
+        let blurred = bilateral_blur(&occlusion, &depth_of, settings.blur_radius, 1, 0, settings.radius);
+        let blurred = bilateral_blur(&blurred, &depth_of, settings.blur_radius, 0, 1, settings.radius);
+        AmbientOcclusion { buffer: blurred }
+    }
+
+    // Accessibility of the pixel at a screen position
+    pub fn at(&self, p: Vec2f) -> f32 {
+        let x = (p.x.max(0.0) as usize).min(self.buffer.width - 1);
+        let y = (p.y.max(0.0) as usize).min(self.buffer.height - 1);
+        self.buffer.get(x, y)
+    }
+}
+
+// Gaussian blur along (dx, dy), whose weights fade with the difference of
+// camera space depth so that the occlusion doesn't bleed over the edges.
+// Background pixels are left untouched.
+fn bilateral_blur<F: Fn(usize, usize) -> Option<f32>>(src: &Framebuffer<f32>, depth: &F, radius: usize, dx: usize, dy: usize, depth_scale: f32) -> Framebuffer<f32> {
+    let mut dst = src.clone();
+    if radius == 0 {
+        return dst;
+    }
+    let sigma = radius as f32 / 2.0;
+    for y in 0..src.height {
+        for x in 0..src.width {
+            let z = match depth(x, y) {
+                Some(z) => z,
+                None => continue,
+            };
+            let (mut total, mut weight) = (0.0, 0.0);
+            for i in -(radius as isize)..=radius as isize {
+                let (nx, ny) = (x as isize + i * dx as isize, y as isize + i * dy as isize);
+                if nx < 0 || ny < 0 || nx >= src.width as isize || ny >= src.height as isize {
+                    continue;
+                }
+                let (nx, ny) = (nx as usize, ny as usize);
+                if let Some(nz) = depth(nx, ny) {
+                    let dz = (nz - z) / (0.25 * depth_scale);
+                    let w = (-(i * i) as f32 / (2.0 * sigma * sigma) - dz * dz).exp();
+                    total += src.get(nx, ny) * w;
+                    weight += w;
+                }
+            }
+            dst.set(x, y, total / weight);
+        }
+    }
+    dst
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ssao_test() {
+        // A floor at z = 0 seen from above, with a step rising to z = 0.3 on
+        // its right half. Orthographic, one pixel is 0.1 units.
+        let (width, height) = (32, 8);
+        let screen_from_camera = Mat44::new(
+            10.0, 0.0, 0.0, 0.0,
+            0.0, 10.0, 0.0, 0.0,
+            0.0, 0.0, 10.0, 0.0,
+            0.0, 0.0, 0.0, 1.0,
+        );
+        let mut depth = Framebuffer::new(width, height, f32::MIN);
+        for y in 0..height {
+            for x in 0..width {
+                depth.set(x, y, if x < width / 2 { 0.0 } else { 3.0 });
+            }
+        }
+        depth.set(0, 0, f32::MIN);
+
+        let settings = SsaoSettings { radius: 0.4, blur_radius: 0, ..SsaoSettings::default() };
+        let ao = AmbientOcclusion::new(&settings, &depth, screen_from_camera, f32::MIN);
+
+        // Open floor and top of the step are not occluded, the floor at the
+        // foot of the step is
+        assert_eq!(ao.buffer.get(4, 4), 1.0);
+        assert_eq!(ao.buffer.get(width - 4, 4), 1.0);
+        assert!(ao.buffer.get(width / 2 - 1, 4) < 0.9);
+        assert!(ao.buffer.get(width / 2 - 1, 4) < ao.buffer.get(width / 2 - 3, 4));
+        assert_eq!(ao.buffer.get(0, 0), 1.0);
+        assert_eq!(ao.at(Vec2f::new(4.5, 4.5)), ao.buffer.get(4, 4));
+
+        // The blur doesn't spread the occlusion to the top of the step
+        let blurred = AmbientOcclusion::new(&SsaoSettings { blur_radius: 3, ..settings }, &depth, screen_from_camera, f32::MIN);
+        assert!((blurred.buffer.get(width / 2, 4) - ao.buffer.get(width / 2, 4)).abs() < 0.01);
+    }
+}
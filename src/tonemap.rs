@@ -0,0 +1,125 @@
+use color;
+use framebuffer::Framebuffer;
+use math::{Vec3f, Vec4f};
+use ppm;
+
+// Tone-mapping maps the HDR scene radiance into the displayable [0, 1]
+// range before it gets encoded into the 8-bit output image.
+
+#[derive(Debug, Clone, Copy)]
+pub enum ToneMapOperator {
+    // No tone-mapping, values are clamped and sRGB encoded
+    Clamp,
+    // Values are clamped then gamma encoded, instead of using the sRGB curve
+    Gamma { gamma: f32 },
+    // Extended Reinhard, `white` is the smallest value mapped to 1.0
+    Reinhard { white: f32 },
+    // Narkowicz's fit of the ACES filmic curve
+    Aces,
+    // John Hable's Uncharted 2 filmic curve, `white` is the linear white point
+    Hable { white: f32 },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ToneMapping {
+    pub operator: ToneMapOperator,
+    pub exposure: f32,
+}
+
+impl Default for ToneMapping {
+    fn default() -> ToneMapping {
+        ToneMapping {
+            operator: ToneMapOperator::Clamp,
+            exposure: 1.0,
+        }
+    }
+}
+
+fn hable_partial(x: f32) -> f32 {
+    const A: f32 = 0.15; // Shoulder strength
+    const B: f32 = 0.50; // Linear strength
+    const C: f32 = 0.10; // Linear angle
+    const D: f32 = 0.20; // Toe strength
+    const E: f32 = 0.02; // Toe numerator
+    const F: f32 = 0.30; // Toe denominator
+
+    ((x * (A * x + C * B) + D * E) / (x * (A * x + B) + D * F)) - E / F
+}
+
+impl ToneMapOperator {
+    // Maps one linear channel to the display linear [0, 1] range
+    pub fn map(self, c: f32) -> f32 {
+        let c = c.max(0.0);
+        let res = match self {
+            ToneMapOperator::Clamp | ToneMapOperator::Gamma { .. } => c,
+            ToneMapOperator::Reinhard { white } => c * (1.0 + c / (white * white)) / (1.0 + c),
+            ToneMapOperator::Aces => (c * (2.51 * c + 0.03)) / (c * (2.43 * c + 0.59) + 0.14),
+            ToneMapOperator::Hable { white } => {
+                const EXPOSURE_BIAS: f32 = 2.0;
+                hable_partial(c * EXPOSURE_BIAS) / hable_partial(white)
+            }
+        };
+        res.clamp(0.0, 1.0)
+    }
+}
+
+impl ToneMapping {
+    pub fn map(&self, c: Vec3f) -> Vec3f {
+        let c = c * self.exposure;
+        Vec3f::new(self.operator.map(c.x), self.operator.map(c.y), self.operator.map(c.z))
+    }
+
+    // Input has premultiplied alpha, output has straight alpha
+    pub fn encode(&self, c: Vec4f) -> ppm::RGB {
+        let a = c.w.clamp(0.0, 1.0);
+        let c = if a > 0.0 { c.xyz() * (1.0 / a) } else { c.xyz() };
+        let c = self.map(c);
+        let res = match self.operator {
+            ToneMapOperator::Gamma { gamma } => {
+                let encode = |c: f32| (c.powf(1.0 / gamma) * 255.0 + 0.5) as u8;
+                ppm::RGB::new(encode(c.x), encode(c.y), encode(c.z))
+            }
+            _ => color::encode_srgb8(c),
+        };
+        ppm::RGB::new_rgba(res.r, res.g, res.b, (a * 255.0 + 0.5) as u8)
+    }
+
+    // Full screen pass from the HDR framebuffer to the output image
+    pub fn apply(&self, hdr: &Framebuffer<Vec4f>, image: &mut ppm::Image) {
+        assert!(hdr.width == image.width && hdr.height == image.height);
+        for y in 0..hdr.height {
+            for x in 0..hdr.width {
+                image.set(x, y, self.encode(hdr.get(x, y)));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tonemap_range_test() {
+        let operators = [
+            ToneMapOperator::Clamp,
+            ToneMapOperator::Gamma { gamma: 2.2 },
+            ToneMapOperator::Reinhard { white: 4.0 },
+            ToneMapOperator::Aces,
+            ToneMapOperator::Hable { white: 11.2 },
+        ];
+
+        for op in operators.iter() {
+            assert!(op.map(0.0).abs() < 0.001);
+            let mut prev = 0.0;
+            for i in 0..100 {
+                let v = op.map(i as f32 * 0.5);
+                assert!(v >= prev && v <= 1.0);
+                prev = v;
+            }
+        }
+
+        let reinhard = ToneMapOperator::Reinhard { white: 4.0 };
+        assert!((reinhard.map(4.0) - 1.0).abs() < 0.001);
+    }
+}